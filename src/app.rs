@@ -1,16 +1,29 @@
 use crate::audio_thread::create_audio_thread;
 use crate::audio_thread::AudioCommand;
+use crate::audio_thread::AudioEvent;
 use crate::audio_thread::AudioState;
-use crate::audio_track;
+use crate::audio_track::ResumeSettings;
 use crate::audio_track::Track;
+use crate::audio_track::TrackId;
+use crate::crossfade::CrossfadeSettings;
+use crate::equalizer::EqSettings;
+use crate::library::LibrarySettings;
+use crate::library_thread::create_library_thread;
+use crate::library_thread::LibraryCommand;
+use crate::library_thread::LibraryEvent;
+use crate::loudness::NormalizationSettings;
 use crate::playlist::Playlist;
 use crate::queue::Queue;
+use crate::speed::SpeedSettings;
 use crate::ui;
+use crate::ui::problems::{Problem, Toast};
+use crate::visualizer::Visualizer;
+use crate::waveform::Waveform;
+use std::collections::BTreeSet;
 
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)]
 pub struct TemplateApp {
-    pub audio_state: AudioState,
     pub volume: f32,
     pub track_progress: f32,
     pub track_duration: f32,
@@ -19,33 +32,158 @@ pub struct TemplateApp {
     pub new_playlist_name: String,
     pub playlist_creation_error: Option<String>,
     pub playlist_list: Vec<Playlist>,
+    pub library_settings: LibrarySettings,
+    pub crossfade: CrossfadeSettings,
+    pub eq_settings: EqSettings,
+    pub normalization: NormalizationSettings,
+    pub speed: SpeedSettings,
+    pub resume: ResumeSettings,
+    /// The chosen output device, `None` for the system default.
+    pub output_device: Option<String>,
+    /// Tracks that failed to play, until dismissed.
+    pub problems: Vec<Problem>,
 
+    #[serde(skip)]
+    pub audio_state: AudioState,
     #[serde(skip)]
     pub audio_thread_sender: std::sync::mpsc::Sender<AudioCommand>,
     #[serde(skip)]
+    pub audio_receiver: std::sync::mpsc::Receiver<AudioEvent>,
+    #[serde(skip)]
+    pub visualizer: Visualizer,
+    #[serde(skip)]
     pub current_playlist: Option<String>,
     #[serde(skip)]
     pub track_list: Vec<Track>,
     #[serde(skip)]
-    pub current_track: Option<String>,
+    pub show_queue: bool,
+    #[serde(skip)]
+    pub show_now_playing: bool,
+    /// Positions in the queue's play order ticked for removal, and the
+    /// queue's revision when they were ticked.
+    #[serde(skip)]
+    pub queue_selection: BTreeSet<usize>,
+    #[serde(skip)]
+    pub queue_selection_revision: u64,
+    /// The position of the queue row being dragged, and the queue's revision
+    /// when the drag started.
+    #[serde(skip)]
+    pub queue_drag: Option<(usize, u64)>,
+    #[serde(skip)]
+    pub current_track: Option<Track>,
+    /// The track last handed to the audio thread to follow the current one.
+    #[serde(skip)]
+    pub next_track: Option<Track>,
+    /// Where the seek bar is being dragged to, in seconds. It only seeks
+    /// there once let go.
+    #[serde(skip)]
+    pub seek_preview: Option<f32>,
+    /// The waveform of the current track, once the library has it.
+    #[serde(skip)]
+    pub waveform: Option<(TrackId, Waveform)>,
+    /// The A and B points of the loop in the current track, in seconds.
+    #[serde(skip)]
+    pub loop_start: Option<f32>,
+    #[serde(skip)]
+    pub loop_end: Option<f32>,
+    /// Where the seek bar was right-clicked, in seconds, for its menu to set
+    /// loop points at.
+    #[serde(skip)]
+    pub seek_menu_at: Option<f32>,
+    #[serde(skip)]
+    pub new_bookmark_name: String,
+    #[serde(skip)]
+    pub library_sender: std::sync::mpsc::Sender<LibraryCommand>,
+    #[serde(skip)]
+    pub library_receiver: std::sync::mpsc::Receiver<LibraryEvent>,
+    #[serde(skip)]
+    pub library_tracks: Vec<Track>,
+    /// Whether the library has been scanned since starting. Until then it
+    /// may not have every track yet, so nothing is marked missing.
+    #[serde(skip)]
+    pub library_scanned: bool,
+    #[serde(skip)]
+    pub output_devices: Vec<String>,
+    #[serde(skip)]
+    pub active_output_device: Option<String>,
+    #[serde(skip)]
+    pub no_output_device: bool,
+    #[serde(skip)]
+    pub show_settings: bool,
+    #[serde(skip)]
+    pub new_library_root: String,
+    #[serde(skip)]
+    pub new_include_pattern: String,
+    #[serde(skip)]
+    pub new_exclude_pattern: String,
+    #[serde(skip)]
+    pub show_equalizer: bool,
+    #[serde(skip)]
+    pub show_problems: bool,
+    #[serde(skip)]
+    pub toasts: Vec<Toast>,
+    #[serde(skip)]
+    pub new_eq_preset_name: String,
+    #[serde(skip)]
+    pub new_eq_genre: String,
 }
 
 impl Default for TemplateApp {
     fn default() -> Self {
+        let (audio_thread_sender, audio_receiver, visualizer) = create_audio_thread();
+        let (library_sender, library_receiver) = create_library_thread();
         Self {
             audio_state: AudioState::Empty,
-            audio_thread_sender: create_audio_thread(),
+            audio_thread_sender,
+            audio_receiver,
+            visualizer,
             current_track: None,
+            next_track: None,
+            waveform: None,
+            loop_start: None,
+            loop_end: None,
+            seek_menu_at: None,
+            new_bookmark_name: String::new(),
             new_playlist_name: String::new(),
             playlist_creation_error: None,
             playlist_list: Vec::new(),
             queue: Queue::new(),
             show_playlist_input: false,
             track_duration: 0.0,
-            track_list: audio_track::get_tracks(),
+            track_list: Vec::new(),
+            show_queue: false,
+            show_now_playing: false,
+            queue_selection: BTreeSet::new(),
+            queue_selection_revision: 0,
+            queue_drag: None,
             track_progress: 0.0,
+            seek_preview: None,
             volume: 1.0,
             current_playlist: None,
+            library_sender,
+            library_receiver,
+            library_tracks: Vec::new(),
+            library_scanned: false,
+            library_settings: LibrarySettings::default(),
+            crossfade: CrossfadeSettings::default(),
+            eq_settings: EqSettings::default(),
+            normalization: NormalizationSettings::default(),
+            speed: SpeedSettings::default(),
+            resume: ResumeSettings::default(),
+            output_device: None,
+            problems: Vec::new(),
+            show_problems: false,
+            toasts: Vec::new(),
+            output_devices: Vec::new(),
+            active_output_device: None,
+            no_output_device: false,
+            show_equalizer: false,
+            new_eq_preset_name: String::new(),
+            new_eq_genre: String::new(),
+            show_settings: false,
+            new_library_root: String::new(),
+            new_include_pattern: String::new(),
+            new_exclude_pattern: String::new(),
         }
     }
 }
@@ -58,11 +196,45 @@ impl TemplateApp {
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+        let mut app: TemplateApp = match cc.storage {
+            Some(storage) => eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default(),
+            None => Default::default(),
+        };
+
+        // The audio thread waits briefly for the device to open before it
+        // falls back to the default one.
+        app.send_audio(AudioCommand::SetOutputDevice(app.output_device.clone()));
+
+        // The audio thread starts at full volume, without crossfading or
+        // equalizer, with the default normalization and at normal speed, and
+        // resumes long tracks.
+        app.send_audio(AudioCommand::SetVolume(app.volume));
+        app.send_audio(AudioCommand::SetCrossfade(app.crossfade.clone()));
+        app.send_audio(AudioCommand::SetEqualizer(app.eq_settings.clone()));
+        app.send_audio(AudioCommand::SetNormalization(app.normalization.clone()));
+        app.send_audio(AudioCommand::SetSpeed(app.speed.clone()));
+        app.send_audio(AudioCommand::SetResume(app.resume.clone()));
+
+        // Bring the library up to date with the folders from the saved settings.
+        app.send_library(LibraryCommand::Rescan(app.library_settings.clone()));
+
+        app
+    }
+
+    /// Hands a command to the audio thread, telling the user if it has
+    /// stopped.
+    pub fn send_audio(&mut self, command: AudioCommand) {
+        if self.audio_thread_sender.send(command).is_err() {
+            ui::problems::report_message(self, "The audio thread has stopped".to_string());
         }
+    }
 
-        Default::default()
+    /// Hands a command to the library thread, telling the user if it has
+    /// stopped.
+    pub fn send_library(&mut self, command: LibraryCommand) {
+        if self.library_sender.send(command).is_err() {
+            ui::problems::report_message(self, "The library thread has stopped".to_string());
+        }
     }
 }
 
@@ -72,6 +244,38 @@ impl eframe::App for TemplateApp {
         eframe::set_value(storage, eframe::APP_KEY, self);
     }
 
+    /// Called once on shutdown, after `save`.
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        let Some(track) = &self.current_track else {
+            return;
+        };
+        let progress = std::time::Duration::from_secs_f32(self.track_progress);
+        let Some(position) = self.resume.position_to_save(track, progress) else {
+            return;
+        };
+        let Some(id) = track.id else {
+            return;
+        };
+        let command = LibraryCommand::SaveResumePosition {
+            track: id,
+            position,
+        };
+        if self.library_sender.send(command).is_err() {
+            return;
+        }
+        // The library thread goes down with the app, so wait a moment for it
+        // to have saved
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        loop {
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            match self.library_receiver.recv_timeout(left) {
+                Ok(LibraryEvent::ResumePositionSaved { track, .. }) if track == id => break,
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+    }
+
     // Called each time the UI needs repainting, which may be many times per second.
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // Update the current app state
@@ -96,5 +300,18 @@ impl eframe::App for TemplateApp {
         // playlist.
         ui::central_panel::show_central_panel(ctx, self);
 
+        // Settings Window:
+        // Responsible for editing the library folders and scan patterns.
+        ui::settings_window::show_settings_window(ctx, self);
+
+        // Equalizer Window:
+        // Responsible for editing the equalizer presets and which tracks use
+        // them.
+        ui::equalizer_window::show_equalizer_window(ctx, self);
+
+        // Problems Window and Toasts:
+        // Responsible for showing what failed to play.
+        ui::problems::show_problems_window(ctx, self);
+        ui::problems::show_toasts(ctx, self);
     }
 }
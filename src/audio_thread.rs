@@ -1,26 +1,166 @@
-use rodio::decoder::Decoder;
-use rodio::OutputStream;
-use rodio::Sink;
-use rodio::Source;
+use crate::audio_track::{ResumeSettings, Track};
+use crate::crossfade::CrossfadeSettings;
+use crate::decoder::{DecodeError, TrackDecoder};
+use crate::equalizer::EqSettings;
+use crate::loudness::NormalizationSettings;
+use crate::output::{Backend, CpalBackend, NullBackend, Output};
+use crate::player::{
+    LoadedLoop, LoadedTrack, LoopRegion, OutputFormat, Player, PlayerCommand, PlayerEvent,
+};
+use crate::speed::SpeedSettings;
+use crate::visualizer::Visualizer;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufReader;
-use std::sync::mpsc::{self, Sender};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::thread;
-use std::thread::sleep;
-use std::time::Duration;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+// How often the audio thread reports the playback position while playing.
+const POSITION_TICK: Duration = Duration::from_millis(100);
+// How often the output devices are listed again, to notice devices being
+// plugged in or removed.
+const DEVICE_POLL: Duration = Duration::from_secs(2);
+// Going back to the previous track only happens this close to the start of
+// the current one, further in it is restarted instead.
+const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
 
 pub enum AudioCommand {
-    GetProgress(Sender<Duration>),
-    GetState(Sender<AudioState>),
-    GetTrackDuration(Sender<Duration>),
     Pause,
     Play,
-    PlaySong(String),
-    SetProgress(f32, String),
+    PlaySong(Track),
+    /// The track to play once the current one ends, decoded ahead of time so
+    /// it follows without a gap.
+    SetNext(Option<Track>),
+    SetProgress(f32),
     SetVolume(f32),
+    SetCrossfade(CrossfadeSettings),
+    SetEqualizer(EqSettings),
+    SetNormalization(NormalizationSettings),
+    SetSpeed(SpeedSettings),
+    /// Which tracks pick up where they were left when played.
+    SetResume(ResumeSettings),
+    /// Loops a section of the current track until it is cleared or another
+    /// track starts.
+    SetLoop(Option<LoopRegion>),
+    /// Plays through the named device, or the system default for `None`.
+    SetOutputDevice(Option<String>),
     Skip,
+    /// Restarts the current track, or plays the given track instead when the
+    /// current one has only just started.
+    Previous(Option<Track>),
+}
+
+/// Published by the audio thread whenever something about playback changes,
+/// so the UI never has to wait on the audio thread.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioEvent {
+    StateChanged(AudioState),
+    TrackStarted {
+        track: Box<Track>,
+        duration: Option<Duration>,
+        start: TrackStart,
+    },
+    /// Sent every `POSITION_TICK` while playing, and after seeking.
+    Position(Duration),
+    /// The current track played through to its end or was skipped.
+    TrackEnded,
+    /// The names of the output devices, whenever they change.
+    OutputDevices(Vec<String>),
+    /// The device playback now goes to, `None` when there is no device to
+    /// play through.
+    OutputChanged(Option<String>),
+    Error(AudioError),
+}
+
+/// Something that went wrong in the audio thread. Playback carries on past
+/// it: tracks that can't be played are passed over, and without a device
+/// playback waits for one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioError {
+    /// The track couldn't be opened, so it wasn't played.
+    Unplayable {
+        track: Box<Track>,
+        error: DecodeError,
+    },
+    /// The track stopped decoding partway and was cut short.
+    EndedEarly {
+        track: Box<Track>,
+        error: DecodeError,
+    },
+    /// Seeking in the track failed.
+    Seek {
+        track: Box<Track>,
+        error: DecodeError,
+    },
+    /// The loop region couldn't be decoded, so the track plays on without
+    /// looping.
+    Loop {
+        track: Box<Track>,
+        error: DecodeError,
+    },
+    /// Damaged parts of the track were skipped.
+    PacketsDropped { track: Box<Track>, count: usize },
+    /// The output device couldn't be opened.
+    Output { device: String, error: String },
+    /// The output devices couldn't be listed.
+    Devices { error: String },
+}
+
+impl AudioError {
+    /// The track the error is about, if it is about one.
+    pub fn track(&self) -> Option<&Track> {
+        match self {
+            AudioError::Unplayable { track, .. }
+            | AudioError::EndedEarly { track, .. }
+            | AudioError::Seek { track, .. }
+            | AudioError::Loop { track, .. }
+            | AudioError::PacketsDropped { track, .. } => Some(track),
+            AudioError::Output { .. } | AudioError::Devices { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Unplayable { track, error } => {
+                write!(f, "Could not play {}: {}", track.title, error)
+            }
+            AudioError::EndedEarly { track, error } => {
+                write!(f, "{} ended early: {}", track.title, error)
+            }
+            AudioError::Seek { track, error } => {
+                write!(f, "Could not seek in {}: {}", track.title, error)
+            }
+            AudioError::Loop { track, error } => {
+                write!(f, "Could not loop {}: {}", track.title, error)
+            }
+            AudioError::PacketsDropped { track, count: 1 } => {
+                write!(f, "Skipped 1 bad packet in {}", track.title)
+            }
+            AudioError::PacketsDropped { track, count } => {
+                write!(f, "Skipped {} bad packets in {}", count, track.title)
+            }
+            AudioError::Output { device, error } => {
+                write!(f, "Could not open {}: {}", device, error)
+            }
+            AudioError::Devices { error } => {
+                write!(f, "Could not list output devices: {}", error)
+            }
+        }
+    }
+}
+
+/// How a track came to be playing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackStart {
+    /// Asked for with `PlaySong`.
+    Played,
+    /// The track given with `SetNext`, after the one before it.
+    Next,
+    /// The track given with `Previous`.
+    Previous,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
@@ -30,161 +170,580 @@ pub enum AudioState {
     Empty,
 }
 
-pub fn create_audio_thread() -> Sender<AudioCommand> {
+/// Starts the audio thread on the sound card, or with no sound at all when
+/// `RUSTIFY_NULL_AUDIO` is set, for running on machines without sound
+/// hardware such as CI.
+pub fn create_audio_thread() -> (Sender<AudioCommand>, Receiver<AudioEvent>, Visualizer) {
+    if std::env::var_os("RUSTIFY_NULL_AUDIO").is_some() {
+        let format = OutputFormat {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        return spawn_audio_thread(NullBackend::new(format, 1.0));
+    }
+    spawn_audio_thread(CpalBackend)
+}
+
+/// Starts an audio thread that plays through `backend`. The visualizer is
+/// fed everything it plays.
+pub fn spawn_audio_thread(
+    backend: impl Backend,
+) -> (Sender<AudioCommand>, Receiver<AudioEvent>, Visualizer) {
     let (sender, receiver) = mpsc::channel();
+    let (event_sender, event_receiver) = mpsc::channel();
+    let (visualizer, tap) = Visualizer::new();
 
     thread::Builder::new()
         .name("Audio Thread".to_string())
         .spawn(move || {
             let mut current_state = AudioState::Empty; // Initial state
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-            let mut sink: Sink = rodio::Sink::try_new(&stream_handle).unwrap();
-            let mut source: Decoder<BufReader<File>>;
-            let mut start_time: Option<Instant> = None;
-            let mut last_pause_time: Option<Instant> = None;
-            let mut track_duration: Option<Duration> = None;
-
-            for command in receiver {
-                match command {
+            let mut volume = 1.0;
+            let mut resume = ResumeSettings::default();
+            // The track the player last started, to open again for seeking.
+            let mut current_track: Option<Track> = None;
 
-                    AudioCommand::GetProgress(sender) => {
-                        // Debugging:
-                        // println!("Current state: {:?}", current_state);
-                        let progress = match current_state {
-                            AudioState::Empty => {
-                                Duration::from_secs(0)
-                            },
-                            AudioState::Paused => {
-                                last_pause_time.map_or(Duration::from_secs(0), |pause_time| {
-                                    start_time.map_or(Duration::from_secs(0), |start| pause_time.duration_since(start))
-                                })
-                            },
-                            AudioState::Playing => {
-                                start_time.map_or(Duration::from_secs(0), |start| start.elapsed())
-                            },
-                        };
+            // A single player is used for the whole session, it moves from
+            // track to track on a thread of its own. Its format is set to the
+            // device's once one is opened, so rodio passes the samples
+            // through untouched.
+            let format = OutputFormat {
+                channels: 2,
+                sample_rate: 44100,
+            };
+            let (mut player, player_handle) = Player::new(format);
+            player.set_tap(tap);
+            thread::Builder::new()
+                .name("Player Thread".to_string())
+                .spawn(move || player.run())
+                .unwrap();
 
-                        // Debugging:
-                        // println!("Progress: {:?}", progress);
-                        let _ = sender.send(progress);
+            // Nothing is played, and the player doesn't move on, while there
+            // is no output. The device is opened on the first check, once the
+            // saved choice of device has had a chance to arrive.
+            let mut output: Option<Box<dyn Output>> = None;
+            let mut preferred_device: Option<String> = None;
+            let mut devices: Vec<String> = Vec::new();
+            // Whether listing the devices failed last time, so a failure is
+            // only reported once until listing works again.
+            let mut listing_failed = false;
+            let mut last_device_check: Option<Instant> = None;
+            // Why each track handed to the player with `Play` is started, in
+            // the order the player will start them.
+            let mut pending_starts: VecDeque<TrackStart> = VecDeque::new();
 
-                        // Reset last_pause_time to None when playing
-                        if current_state == AudioState::Playing {
-                            last_pause_time = None;
+            // The UI may be gone when shutting down, events are best effort.
+            let publish = |event: AudioEvent| {
+                let _ = event_sender.send(event);
+            };
+            // Opens a track to be played, where it was left if it resumes. A
+            // track that can't be moved there starts from the beginning.
+            let open = |track: Track, resume: &ResumeSettings| {
+                if let Some(position) = track.track_progress.filter(|_| resume.applies_to(&track)) {
+                    match load(track.clone(), position) {
+                        Ok(loaded) => return Some(loaded),
+                        Err(error @ AudioError::Seek { .. }) => publish(AudioEvent::Error(error)),
+                        Err(error) => {
+                            publish(AudioEvent::Error(error));
+                            return None;
                         }
-                    },
+                    }
+                }
+                match load(track, Duration::ZERO) {
+                    Ok(loaded) => Some(loaded),
+                    Err(error) => {
+                        publish(AudioEvent::Error(error));
+                        None
+                    }
+                }
+            };
+            // The decoder is opened and seeked here, the player only swaps it
+            // in. When seeking fails, playback carries on where it is.
+            let seek = |track: Track, position: Duration| match load(track, position) {
+                Ok(loaded) => {
+                    let _ = player_handle.commands.send(PlayerCommand::Seek(loaded));
+                    player_handle.position.set(position);
+                    publish(AudioEvent::Position(position));
+                }
+                Err(error) => publish(AudioEvent::Error(error)),
+            };
 
-                    AudioCommand::SetProgress(progress, file_path) => {
-                        sink.stop();
-                        let file = match File::open(&file_path) {
-                            Ok(f) => BufReader::new(f),
-                            Err(e) => {
-                                eprintln!("Error opening file: {}", e);
-                                continue;
-                            }
-                        };
-                        source = Decoder::new_wav(file).unwrap();
-                        // Set the position where the track should start playing from, based on the progress.
-                        let skip_duration = Duration::from_secs_f32(progress);
-                        sink.append(source.skip_duration(skip_duration));
-
-                        // Adjust the start_time to correctly reflect the new starting point.
-                        // If we're currently playing, we should resume playing from the new position.
-                        // If we're paused, we adjust the timing but do not start playback.
-                        if current_state == AudioState::Playing {
-                            sink.play();
-                            start_time = Some(Instant::now() - skip_duration);
-                        } else if current_state == AudioState::Paused {
-                            start_time = Some(Instant::now() - skip_duration);
-                            last_pause_time = Some(Instant::now());
-                        }
-                    },
-
-                    AudioCommand::Play => {
-                        if let Some(last_pause) = last_pause_time {
-                            let pause_duration = Instant::now().duration_since(last_pause);
-                            start_time = Some(start_time.unwrap_or(Instant::now()) + pause_duration);
-                        } else {
-                            start_time = Some(Instant::now());
-                        }
-
-                        last_pause_time = None;
-                        sink.play();
-                        current_state = AudioState::Playing;
-                    },
-
-                    AudioCommand::PlaySong(file_path) => {
-                        let file = match File::open(&file_path) {
-                            Ok(f) => BufReader::new(f),
-                            Err(e) => {
-                                eprintln!("Error opening file: {}", e);
-                                continue;
-                            }
-                        };
-
-                        // Attempt to decode the new audio file
-                        let new_source = match Decoder::new_wav(file) {
-                            Ok(source) => source,
-                            Err(e) => {
-                                eprintln!("Error decoding file: {}", e);
-                                continue;
+            loop {
+                while let Ok(event) = player_handle.events.try_recv() {
+                    match event {
+                        PlayerEvent::Started {
+                            track,
+                            duration,
+                            was_next,
+                        } => {
+                            let start = if was_next {
+                                TrackStart::Next
+                            } else {
+                                pending_starts.pop_front().unwrap_or(TrackStart::Played)
+                            };
+                            current_track = Some(track.as_ref().clone());
+                            publish(AudioEvent::TrackStarted {
+                                track,
+                                duration,
+                                start,
+                            });
+                            if current_state != AudioState::Playing {
+                                current_state = AudioState::Playing;
+                                publish(AudioEvent::StateChanged(current_state));
                             }
-                        };
+                        }
+                        PlayerEvent::Ended => publish(AudioEvent::TrackEnded),
+                        PlayerEvent::Idle => {
+                            current_track = None;
+                            current_state = AudioState::Empty;
+                            publish(AudioEvent::StateChanged(current_state));
+                        }
+                        PlayerEvent::DecodeFailed { track, error } => {
+                            publish(AudioEvent::Error(AudioError::EndedEarly { track, error }));
+                        }
+                        PlayerEvent::PacketsDropped { track, count } => {
+                            let error = AudioError::PacketsDropped { track, count };
+                            publish(AudioEvent::Error(error));
+                        }
+                    }
+                }
 
-                        // Calculate duration of the track
-                        track_duration = new_source.total_duration();
+                if current_state == AudioState::Playing {
+                    publish(AudioEvent::Position(player_handle.position.get()));
+                }
 
-                        // Create a new sink for the audio output device
-                        let new_sink: Sink = rodio::Sink::try_new(&stream_handle).unwrap();
+                let command = match receiver.recv_timeout(POSITION_TICK) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
 
-                        // Clear the old sink (optional, depends on whether you want to overlap or immediately stop previous audio)
-                        sink.stop();
+                match command {
+                    Some(AudioCommand::SetOutputDevice(name)) => {
+                        preferred_device = name;
+                        last_device_check = None;
+                    }
+                    Some(AudioCommand::SetProgress(progress)) => {
+                        // The sink keeps playing or stays paused.
+                        if let Some(track) = current_track.clone() {
+                            seek(track, Duration::from_secs_f32(progress));
+                        }
+                    }
 
-                        // Append the new source to the new sink and start playing
-                        new_sink.append(new_source);
-                        new_sink.play();
+                    Some(AudioCommand::Play) => {
+                        if current_state != AudioState::Paused {
+                            continue;
+                        }
+                        if let Some(output) = &output {
+                            output.play();
+                        }
+                        current_state = AudioState::Playing;
+                        publish(AudioEvent::StateChanged(current_state));
+                    }
 
-                        // Update the current sink to the new sink
-                        sink = new_sink;
+                    Some(AudioCommand::PlaySong(track)) => {
+                        // Attempt to decode the new audio file, whatever its format
+                        match open(track, &resume) {
+                            Some(loaded) => {
+                                let _ = player_handle.commands.send(PlayerCommand::Play(loaded));
+                                pending_starts.push_back(TrackStart::Played);
+                                if let Some(output) = &output {
+                                    output.play();
+                                }
+                            }
+                            // Let the UI move on to the next track
+                            None if current_state == AudioState::Empty => {
+                                publish(AudioEvent::StateChanged(AudioState::Empty));
+                            }
+                            None => (),
+                        }
+                    }
 
-                        current_state = AudioState::Playing;
-                        start_time = Some(Instant::now());
-                        last_pause_time = None;
+                    Some(AudioCommand::SetNext(track)) => {
+                        let loaded = track.and_then(|track| open(track, &resume));
+                        let _ = player_handle.commands.send(PlayerCommand::SetNext(loaded));
                     }
 
-                    AudioCommand::Pause => {
-                        sink.pause();
+                    Some(AudioCommand::Pause) => {
+                        if current_state != AudioState::Playing {
+                            continue;
+                        }
+                        if let Some(output) = &output {
+                            output.pause();
+                        }
                         current_state = AudioState::Paused;
-                        last_pause_time = Some(Instant::now());
-                    },
-                    // Below this nothing should need to be changed
-                    AudioCommand::Skip => {
-                        if sink.len() <= 1 {
-                            sink.stop();
-                            current_state = AudioState::Empty;
-                        } else {
-                            sink.skip_one();
-                            current_state = AudioState::Playing;
+                        publish(AudioEvent::Position(player_handle.position.get()));
+                        publish(AudioEvent::StateChanged(current_state));
+                    }
+                    Some(AudioCommand::Previous(previous)) => {
+                        let near_start = player_handle.position.get() < RESTART_THRESHOLD;
+                        match previous {
+                            Some(track) if near_start => {
+                                if let Some(loaded) = open(track, &resume) {
+                                    let _ =
+                                        player_handle.commands.send(PlayerCommand::Play(loaded));
+                                    pending_starts.push_back(TrackStart::Previous);
+                                    if let Some(output) = &output {
+                                        output.play();
+                                    }
+                                }
+                            }
+                            _ => {
+                                if let Some(track) = current_track.clone() {
+                                    seek(track, Duration::ZERO);
+                                }
+                            }
                         }
                     }
-                    AudioCommand::GetTrackDuration(sender) => {
-                        if current_state == AudioState::Empty {
-                            let _ = sender.send(Duration::from_secs(1));
-                            continue;
+                    Some(AudioCommand::Skip) => {
+                        let _ = player_handle.commands.send(PlayerCommand::Skip);
+                    }
+                    Some(AudioCommand::SetVolume(new_volume)) => {
+                        volume = new_volume;
+                        if let Some(output) = &output {
+                            output.set_volume(volume);
                         }
-                        let _ = sender.send(track_duration.unwrap());
                     }
-                    AudioCommand::GetState(sender) => {
-                        if sink.empty() {
-                            current_state = AudioState::Empty;
+                    Some(AudioCommand::SetCrossfade(settings)) => {
+                        let _ = player_handle
+                            .commands
+                            .send(PlayerCommand::SetCrossfade(settings));
+                    }
+                    Some(AudioCommand::SetEqualizer(settings)) => {
+                        let _ = player_handle
+                            .commands
+                            .send(PlayerCommand::SetEqualizer(settings));
+                    }
+                    Some(AudioCommand::SetNormalization(settings)) => {
+                        let _ = player_handle
+                            .commands
+                            .send(PlayerCommand::SetNormalization(settings));
+                    }
+                    Some(AudioCommand::SetSpeed(settings)) => {
+                        let _ = player_handle
+                            .commands
+                            .send(PlayerCommand::SetSpeed(settings));
+                    }
+                    Some(AudioCommand::SetResume(settings)) => resume = settings,
+                    Some(AudioCommand::SetLoop(region)) => {
+                        // The region is decoded here, the player only plays
+                        // it from memory
+                        let region = region.filter(|region| region.start < region.end);
+                        match (region, &current_track) {
+                            (Some(region), Some(track)) => match load_loop(track.clone(), region) {
+                                Ok(looped) => {
+                                    let _ = player_handle
+                                        .commands
+                                        .send(PlayerCommand::SetLoop(Some(looped)));
+                                }
+                                Err(error) => publish(AudioEvent::Error(error)),
+                            },
+                            (Some(_), None) => (),
+                            (None, _) => {
+                                let _ = player_handle.commands.send(PlayerCommand::SetLoop(None));
+                            }
                         }
-                        sender.send(current_state).unwrap()
                     }
-                    AudioCommand::SetVolume(volume) => sink.set_volume(volume),
+                    None => (),
+                }
+
+                // Falls back to the default device when the chosen one goes
+                // away, and moves back once it returns.
+                if last_device_check.is_some_and(|checked| checked.elapsed() < DEVICE_POLL) {
+                    continue;
                 }
+                let checked_before = last_device_check.is_some();
+                last_device_check = Some(Instant::now());
+                match backend.device_names() {
+                    Ok(names) => {
+                        listing_failed = false;
+                        if names != devices {
+                            devices = names;
+                            publish(AudioEvent::OutputDevices(devices.clone()));
+                        }
+                    }
+                    Err(error) => {
+                        if !listing_failed {
+                            publish(AudioEvent::Error(AudioError::Devices { error }));
+                        }
+                        listing_failed = true;
+                    }
+                }
+                let device_name = backend.resolve_device(preferred_device.as_deref());
+                let current_name = output
+                    .as_ref()
+                    .map(|output| output.device_name().to_string());
+                if device_name == current_name {
+                    if !checked_before {
+                        publish(AudioEvent::OutputChanged(current_name));
+                    }
+                    continue;
+                }
+
+                // The old output stops taking samples before the player moves
+                // on to the new one.
+                drop(output.take());
+                output =
+                    device_name.and_then(|device| match backend.open(&device, &player_handle) {
+                        Ok(output) => Some(output),
+                        Err(error) => {
+                            publish(AudioEvent::Error(AudioError::Output { device, error }));
+                            None
+                        }
+                    });
+                if let Some(output) = &output {
+                    output.set_volume(volume);
+                    if current_state == AudioState::Paused {
+                        output.pause();
+                    }
+                }
+                publish(AudioEvent::OutputChanged(
+                    output
+                        .as_ref()
+                        .map(|output| output.device_name().to_string()),
+                ));
             }
         })
-    .unwrap();
+        .unwrap();
+
+    (sender, event_receiver, visualizer)
+}
+
+// Opens `track` with its decoder moved to `position`.
+fn load(track: Track, position: Duration) -> Result<LoadedTrack, AudioError> {
+    let mut decoder = match TrackDecoder::open(&track.file_path) {
+        Ok(decoder) => decoder,
+        Err(error) => {
+            let track = Box::new(track);
+            return Err(AudioError::Unplayable { track, error });
+        }
+    };
+    if position > Duration::ZERO {
+        if let Err(error) = decoder.seek(position) {
+            let track = Box::new(track);
+            return Err(AudioError::Seek { track, error });
+        }
+    }
+    Ok(LoadedTrack {
+        track,
+        decoder,
+        position,
+    })
+}
+
+// Opens `track` and decodes `region` of it to be looped.
+fn load_loop(track: Track, region: LoopRegion) -> Result<LoadedLoop, AudioError> {
+    let looped = TrackDecoder::open(&track.file_path).and_then(|mut decoder| {
+        decoder.seek(region.start)?;
+        LoadedLoop::read(track.clone(), decoder, region)
+    });
+    looped.map_err(|error| {
+        let track = Box::new(track);
+        AudioError::Loop { track, error }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_track::TrackId;
+
+    const FIRST: &str = "assets/tracks/filler1.wav";
+    const SECOND: &str = "assets/tracks/filler2.wav";
+
+    // The test tracks are mono at 22050 Hz. At 50 times real time a minute
+    // long track plays in a little over a second.
+    fn start() -> (NullBackend, Sender<AudioCommand>, Receiver<AudioEvent>) {
+        let format = OutputFormat {
+            channels: 1,
+            sample_rate: 22050,
+        };
+        let backend = NullBackend::new(format, 50.0);
+        let (sender, receiver, _visualizer) = spawn_audio_thread(backend.clone());
+        (backend, sender, receiver)
+    }
+
+    fn track(file_path: &str) -> Track {
+        Track {
+            file_path: file_path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Waits for the first event `matches` accepts, skipping the others.
+    fn wait_for(
+        receiver: &Receiver<AudioEvent>,
+        matches: impl Fn(&AudioEvent) -> bool,
+    ) -> AudioEvent {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(left) {
+                Ok(event) if matches(&event) => return event,
+                Ok(_) => (),
+                Err(e) => panic!("No matching event: {}", e),
+            }
+        }
+    }
+
+    fn started(file_path: &str, start: TrackStart) -> impl Fn(&AudioEvent) -> bool + '_ {
+        move |event| {
+            matches!(event, AudioEvent::TrackStarted { track, start: s, .. }
+                if track.file_path == file_path && *s == start)
+        }
+    }
+
+    #[test]
+    fn test_play_pause_and_seek() {
+        let (_backend, sender, receiver) = start();
+        sender.send(AudioCommand::PlaySong(track(FIRST))).unwrap();
+        wait_for(&receiver, started(FIRST, TrackStart::Played));
+        wait_for(&receiver, |e| {
+            *e == AudioEvent::StateChanged(AudioState::Playing)
+        });
+
+        sender.send(AudioCommand::Pause).unwrap();
+        wait_for(&receiver, |e| {
+            *e == AudioEvent::StateChanged(AudioState::Paused)
+        });
+        // Nothing is reported while paused.
+        thread::sleep(POSITION_TICK * 3);
+        assert!(receiver
+            .try_iter()
+            .all(|e| !matches!(e, AudioEvent::Position(_))));
+
+        sender.send(AudioCommand::SetProgress(30.0)).unwrap();
+        wait_for(&receiver, |e| {
+            *e == AudioEvent::Position(Duration::from_secs(30))
+        });
+
+        sender.send(AudioCommand::Play).unwrap();
+        wait_for(&receiver, |e| {
+            *e == AudioEvent::StateChanged(AudioState::Playing)
+        });
+        wait_for(
+            &receiver,
+            |e| matches!(e, AudioEvent::Position(position) if *position > Duration::from_secs(30)),
+        );
+    }
+
+    #[test]
+    fn test_resumes_where_left() {
+        let (_backend, sender, receiver) = start();
+        let resume = ResumeSettings {
+            enabled: true,
+            min_duration: Duration::from_secs(60),
+        };
+        sender.send(AudioCommand::SetResume(resume)).unwrap();
+        let mut resuming = track(FIRST);
+        resuming.id = Some(TrackId(1));
+        resuming.duration = Some(Duration::from_secs(60));
+        resuming.track_progress = Some(Duration::from_secs(10));
+        sender.send(AudioCommand::PlaySong(resuming)).unwrap();
+
+        wait_for(&receiver, started(FIRST, TrackStart::Played));
+        let position = wait_for(&receiver, |e| matches!(e, AudioEvent::Position(_)));
+        assert!(
+            matches!(position, AudioEvent::Position(p) if p >= Duration::from_secs(10)),
+            "{:?}",
+            position
+        );
+    }
+
+    #[test]
+    fn test_skip_moves_to_next_track() {
+        let (_backend, sender, receiver) = start();
+        sender.send(AudioCommand::PlaySong(track(FIRST))).unwrap();
+        sender
+            .send(AudioCommand::SetNext(Some(track(SECOND))))
+            .unwrap();
+        wait_for(&receiver, started(FIRST, TrackStart::Played));
+
+        sender.send(AudioCommand::Skip).unwrap();
+        wait_for(&receiver, |e| *e == AudioEvent::TrackEnded);
+        wait_for(&receiver, started(SECOND, TrackStart::Next));
+
+        // Nothing comes after the second track.
+        sender.send(AudioCommand::Skip).unwrap();
+        wait_for(&receiver, |e| {
+            *e == AudioEvent::StateChanged(AudioState::Empty)
+        });
+    }
+
+    #[test]
+    fn test_plays_through_to_next_track() {
+        let (_backend, sender, receiver) = start();
+        sender.send(AudioCommand::PlaySong(track(FIRST))).unwrap();
+        sender
+            .send(AudioCommand::SetNext(Some(track(SECOND))))
+            .unwrap();
+        wait_for(&receiver, started(FIRST, TrackStart::Played));
+
+        sender.send(AudioCommand::SetProgress(59.0)).unwrap();
+        wait_for(&receiver, |e| *e == AudioEvent::TrackEnded);
+        wait_for(&receiver, started(SECOND, TrackStart::Next));
+    }
+
+    #[test]
+    fn test_previous_restarts_then_goes_back() {
+        let (_backend, sender, receiver) = start();
+        sender.send(AudioCommand::PlaySong(track(FIRST))).unwrap();
+        wait_for(&receiver, started(FIRST, TrackStart::Played));
+        // Paused, so the position stays where it is put.
+        sender.send(AudioCommand::Pause).unwrap();
+        sender.send(AudioCommand::SetProgress(30.0)).unwrap();
+
+        sender
+            .send(AudioCommand::Previous(Some(track(SECOND))))
+            .unwrap();
+        wait_for(&receiver, |e| *e == AudioEvent::Position(Duration::ZERO));
+
+        sender
+            .send(AudioCommand::Previous(Some(track(SECOND))))
+            .unwrap();
+        wait_for(&receiver, started(SECOND, TrackStart::Previous));
+    }
+
+    #[test]
+    fn test_unplayable_track_reports_error() {
+        let (_backend, sender, receiver) = start();
+        sender
+            .send(AudioCommand::PlaySong(track("assets/tracks/missing.wav")))
+            .unwrap();
+        let error = wait_for(&receiver, |e| matches!(e, AudioEvent::Error(_)));
+        assert!(matches!(
+            error,
+            AudioEvent::Error(AudioError::Unplayable { track, error: DecodeError::Io(_) })
+                if track.file_path == "assets/tracks/missing.wav"
+        ));
+        wait_for(&receiver, |e| {
+            *e == AudioEvent::StateChanged(AudioState::Empty)
+        });
+    }
+
+    #[test]
+    fn test_falls_back_when_device_disappears() {
+        let (backend, sender, receiver) = start();
+        *backend.devices.lock().unwrap() = vec!["Speakers".to_string(), "Headphones".to_string()];
+        let changed_to = |name: Option<&str>| {
+            let expected = AudioEvent::OutputChanged(name.map(str::to_string));
+            move |e: &AudioEvent| *e == expected
+        };
+
+        sender
+            .send(AudioCommand::SetOutputDevice(Some(
+                "Headphones".to_string(),
+            )))
+            .unwrap();
+        wait_for(&receiver, changed_to(Some("Headphones")));
+
+        backend
+            .devices
+            .lock()
+            .unwrap()
+            .retain(|d| d != "Headphones");
+        wait_for(&receiver, changed_to(Some("Speakers")));
 
-    sender
+        backend.devices.lock().unwrap().clear();
+        wait_for(&receiver, changed_to(None));
+    }
 }
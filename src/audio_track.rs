@@ -1,39 +1,198 @@
+use crate::decoder::{self, DecodeError};
+use crate::tags;
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::path::Path;
 use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+// Positions this close to either end of a track aren't worth resuming at.
+const RESUME_MARGIN: Duration = Duration::from_secs(30);
+
+/// Identifies a track by its row in the library. Unlike the path it stays
+/// the same when the file is moved or renamed.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(transparent)]
+pub struct TrackId(pub i64);
+
+/// A named point in a track to jump back to.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Bookmark {
+    /// The bookmark's row in the library.
+    pub id: i64,
+    pub name: String,
+    pub position: Duration,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+#[serde(default)]
 pub struct Track {
+    /// The track's row in the library, `None` for tracks not read from it.
+    pub id: Option<TrackId>,
     pub title: String,
     pub file_path: String,
-    duration: Option<Duration>,
-    track_progress: Option<Duration>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub duration: Option<Duration>,
+    /// Where playback was left, for long tracks that pick up there again.
+    /// Kept in the library.
+    pub track_progress: Option<Duration>,
+    /// ReplayGain in dB, from the file's tags or else from analyzing it.
+    pub track_gain: Option<f32>,
+    /// The track's highest level, where 1.0 is full scale.
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+    /// Kept in the library, in the order they come up in the track.
+    pub bookmarks: Vec<Bookmark>,
+    /// Set when the file looks like audio but cannot be played, so the track
+    /// can be shown with the reason instead of being dropped from the list.
+    pub error: Option<String>,
+    /// Set on playlist and queue entries whose file has left the library.
+    pub missing: bool,
 }
 
 impl Track {
+    /// Reads a track's tags and duration from its file. Fails for files that
+    /// aren't audio at all; audio that can't be played gets `error` set instead.
     pub fn new(file_path: String) -> Result<Track, String> {
-        let mut title = file_path.split('/').last().unwrap().to_string();
-        title = title.split('.').next().unwrap().to_string();
+        let mut track = Track {
+            title: fallback_title(&file_path),
+            ..Default::default()
+        };
+
+        let mut probed = match decoder::probe(&file_path) {
+            Ok(probed) => probed,
+            Err(e @ DecodeError::UnrecognizedFormat) => return Err(e.to_string()),
+            Err(e) => {
+                track.error = Some(e.to_string());
+                track.file_path = file_path;
+                return Ok(track);
+            }
+        };
+        match decoder::check_codec(&probed) {
+            Ok(()) => (),
+            Err(e @ DecodeError::NoAudioTrack) => return Err(e.to_string()),
+            Err(e) => track.error = Some(e.to_string()),
+        }
+
+        let tags = tags::read_tags(&mut probed);
+        // Only fall back to the file name when the file has no title tag.
+        if let Some(title) = tags.title {
+            track.title = title;
+        }
+        track.artist = tags.artist;
+        track.album = tags.album;
+        track.album_artist = tags.album_artist;
+        track.track_number = tags.track_number;
+        track.disc_number = tags.disc_number;
+        track.year = tags.year;
+        track.genre = tags.genre;
+        track.composer = tags.composer;
+        track.track_gain = tags.track_gain;
+        track.track_peak = tags.track_peak;
+        track.album_gain = tags.album_gain;
+        track.album_peak = tags.album_peak;
+        track.duration = decoder::scan_duration(probed);
+        track.file_path = file_path;
+
+        Ok(track)
+    }
+
+    /// Whether both are the same track: the same library entry, or the same
+    /// file for tracks that aren't in the library.
+    pub fn is_same(&self, other: &Track) -> bool {
+        match (self.id, other.id) {
+            (Some(id), Some(other_id)) => id == other_id,
+            _ => self.file_path == other.file_path,
+        }
+    }
+}
+
+fn fallback_title(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| file_path.to_string())
+}
+
+/// Which tracks remember where they were left: audiobooks, podcasts and
+/// long mixes, told apart from music by their length.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ResumeSettings {
+    pub enabled: bool,
+    pub min_duration: Duration,
+}
 
-        Ok(Track {
-            title,
-            file_path,
-            duration: Some(Duration::from_secs(0)),
-            track_progress: Some(Duration::from_secs(0)),
-        })
+impl Default for ResumeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_duration: Duration::from_secs(20 * 60),
+        }
     }
 }
 
-pub fn get_tracks() -> Vec<Track> {
-    let mut tracks = Vec::new();
-    if let Ok(entries) = fs::read_dir("assets/tracks") {
-        for entry in entries.filter_map(Result::ok) {
-            let file_path: String = entry.path().to_str().unwrap().to_string();
-            let track = Track::new(file_path).unwrap();
-            tracks.push(track);
+impl ResumeSettings {
+    pub fn applies_to(&self, track: &Track) -> bool {
+        self.enabled && track.id.is_some() && track.duration >= Some(self.min_duration)
+    }
+
+    /// What to remember for `track` when it is left at `position`: nothing
+    /// close to the start, and nothing close to the end either, where it
+    /// counts as finished. `None` for tracks that don't resume.
+    pub fn position_to_save(&self, track: &Track, position: Duration) -> Option<Option<Duration>> {
+        if !self.applies_to(track) {
+            return None;
         }
+        let duration = track.duration.unwrap_or_default();
+        let started = position >= RESUME_MARGIN;
+        let finished = position + RESUME_MARGIN >= duration;
+        Some(Some(position).filter(|_| started && !finished))
+    }
+}
+
+/// Sums the known durations of `tracks`, tracks of unknown length count as 0.
+pub fn total_duration(tracks: &[Track]) -> Duration {
+    tracks.iter().filter_map(|track| track.duration).sum()
+}
+
+/// Whether two tracks are on the same album by the same album artist.
+pub fn same_album(a: &Track, b: &Track) -> bool {
+    let album_artist = |track: &Track| track.album_artist.clone().or(track.artist.clone());
+    a.album.is_some() && a.album == b.album && album_artist(a) == album_artist(b)
+}
+
+/// The tracks in `tracks` from the same album as `track`, in disc and track
+/// number order.
+pub fn album_tracks(tracks: &[Track], track: &Track) -> Vec<Track> {
+    let mut album: Vec<Track> = tracks
+        .iter()
+        .filter(|t| same_album(t, track))
+        .cloned()
+        .collect();
+    album.sort_by_key(|t| (t.disc_number, t.track_number));
+    album
+}
+
+/// Formats a duration as "m:ss", or "h:mm:ss" once it passes an hour.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
     }
-    tracks
 }
 
 #[cfg(test)]
@@ -46,10 +205,74 @@ mod tests {
         let track = Track::new(file_path.clone()).unwrap();
 
         assert_eq!(track.file_path, file_path);
-        assert_eq!(track.title, "song.mp3");
-        assert!(track.duration.is_some());
-        assert_eq!(track.duration.unwrap(), Duration::from_secs(0));
-        assert!(track.track_progress.is_some());
-        assert_eq!(track.track_progress.unwrap(), Duration::from_secs(0));
+        assert_eq!(track.title, "song");
+        assert!(track.artist.is_none());
+        assert!(track.duration.is_none());
+        assert!(track.error.is_some());
+        assert!(track.track_progress.is_none());
+    }
+
+    #[test]
+    fn test_track_new_reads_duration() {
+        let track = Track::new("assets/tracks/CantinaBand60.wav".to_string()).unwrap();
+
+        assert_eq!(track.title, "CantinaBand60");
+        assert_eq!(track.duration.unwrap().as_secs(), 60);
+        assert!(track.error.is_none());
+    }
+
+    #[test]
+    fn test_track_new_rejects_non_audio() {
+        assert!(Track::new("Cargo.toml".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_album_tracks_in_order() {
+        let track = |title: &str, album: &str, number: u32| Track {
+            title: title.to_string(),
+            artist: Some("Artist".to_string()),
+            album: Some(album.to_string()),
+            track_number: Some(number),
+            ..Default::default()
+        };
+        let tracks = vec![
+            track("b", "Album", 2),
+            track("other", "Other", 1),
+            track("a", "Album", 1),
+        ];
+        let album = album_tracks(&tracks, &tracks[0]);
+        let titles: Vec<&str> = album.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(titles, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_resume_position_to_save() {
+        let settings = ResumeSettings::default();
+        let mut track = Track {
+            id: Some(TrackId(1)),
+            duration: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        assert_eq!(
+            settings.position_to_save(&track, minutes(10)),
+            Some(Some(minutes(10)))
+        );
+        assert_eq!(
+            settings.position_to_save(&track, Duration::ZERO),
+            Some(None)
+        );
+        assert_eq!(settings.position_to_save(&track, minutes(60)), Some(None));
+
+        // Songs always start from the beginning
+        track.duration = Some(minutes(4));
+        assert_eq!(settings.position_to_save(&track, minutes(2)), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(59)), "0:59");
+        assert_eq!(format_duration(Duration::from_secs(61)), "1:01");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1:02:03");
     }
 }
@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FadeCurve {
+    Linear,
+    /// Keeps the combined loudness steady, the usual choice for music.
+    EqualPower,
+    /// Holds on to the outgoing track longer and brings the new one in late.
+    SCurve,
+}
+
+impl FadeCurve {
+    pub const ALL: [FadeCurve; 3] = [FadeCurve::Linear, FadeCurve::EqualPower, FadeCurve::SCurve];
+
+    /// The gains of the outgoing and incoming track at `progress` through the
+    /// fade, from 0 to 1.
+    pub fn gains(self, progress: f32) -> (f32, f32) {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => (1.0 - progress, progress),
+            FadeCurve::EqualPower => ((progress * FRAC_PI_2).cos(), (progress * FRAC_PI_2).sin()),
+            FadeCurve::SCurve => {
+                let incoming = (1.0 - (progress * 2.0 * FRAC_PI_2).cos()) / 2.0;
+                (1.0 - incoming, incoming)
+            }
+        }
+    }
+}
+
+impl fmt::Display for FadeCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FadeCurve::Linear => write!(f, "Linear"),
+            FadeCurve::EqualPower => write!(f, "Equal power"),
+            FadeCurve::SCurve => write!(f, "S-curve"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct CrossfadeSettings {
+    pub enabled: bool,
+    pub duration: Duration,
+    pub curve: FadeCurve,
+    /// Albums are often mastered to flow into the next track, those are
+    /// played back to back instead.
+    pub skip_same_album: bool,
+}
+
+impl Default for CrossfadeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            duration: Duration::from_secs(5),
+            curve: FadeCurve::EqualPower,
+            skip_same_album: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_curves_go_from_outgoing_to_incoming() {
+        for curve in FadeCurve::ALL {
+            let (outgoing, incoming) = curve.gains(0.0);
+            assert!((outgoing - 1.0).abs() < 1e-6 && incoming.abs() < 1e-6);
+            let (outgoing, incoming) = curve.gains(1.0);
+            assert!(outgoing.abs() < 1e-6 && (incoming - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_equal_power_keeps_power() {
+        for step in 0..=10 {
+            let (outgoing, incoming) = FadeCurve::EqualPower.gains(step as f32 / 10.0);
+            assert!((outgoing * outgoing + incoming * incoming - 1.0).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use crate::opus::OpusDecoder;
+use rodio::Source;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::sync::OnceLock;
+use std::time::Duration;
+use symphonia::core::audio::{SampleBuffer, SignalSpec};
+use symphonia::core::codecs::{self, CodecRegistry, CodecType, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision};
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia::core::units::{Time, TimeBase};
+
+// A decode error in a single packet is not fatal, the packet is dropped and
+// the next one is tried. More than this many in a row ends the track.
+const MAX_DECODE_ERRORS: usize = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    Io(String),
+    UnrecognizedFormat,
+    NoAudioTrack,
+    UnsupportedCodec(String),
+    Decode(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "Could not read file: {}", e),
+            DecodeError::UnrecognizedFormat => write!(f, "Unrecognized file format"),
+            DecodeError::NoAudioTrack => write!(f, "File contains no audio track"),
+            DecodeError::UnsupportedCodec(codec) => write!(f, "Unsupported codec: {}", codec),
+            DecodeError::Decode(e) => write!(f, "Error decoding file: {}", e),
+        }
+    }
+}
+
+impl From<Error> for DecodeError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::IoError(e) => DecodeError::Io(e.to_string()),
+            Error::Unsupported(_) => DecodeError::UnrecognizedFormat,
+            e => DecodeError::Decode(e.to_string()),
+        }
+    }
+}
+
+/// Decodes any file symphonia can demux and decode into interleaved `f32`
+/// samples. The container is detected by sniffing the file contents, the
+/// extension is never consulted.
+pub struct TrackDecoder {
+    file_path: String,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn codecs::Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    spec: SignalSpec,
+    buffer: SampleBuffer<f32>,
+    offset: usize,
+    // Samples from `end` on are encoder padding.
+    end: usize,
+    trim: Option<Trim>,
+    total_duration: Option<Duration>,
+    // Why decoding stopped before the end of the stream, if it did.
+    error: Option<DecodeError>,
+    // Packets dropped because they couldn't be decoded, since last taken.
+    dropped_packets: usize,
+}
+
+/// The encoder delay and padding around the real audio of a stream, for
+/// formats symphonia doesn't trim itself (AAC with an iTunSMPB tag).
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Trim {
+    delay: u64,
+    length: u64,
+    // Frames of delay still to drop, and frames of audio still to play.
+    skip: u64,
+    remaining: u64,
+}
+
+impl TrackDecoder {
+    pub fn open(file_path: &str) -> Result<TrackDecoder, DecodeError> {
+        let mut probed = probe(file_path)?;
+        let itunes_trim = read_itunes_trim(&mut probed);
+        let mut format = probed.format;
+
+        let track = audio_track(format.as_ref()).ok_or(DecodeError::NoAudioTrack)?;
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        // Symphonia already trims streams that declare their delay (MP3 with
+        // a LAME header), only fall back to the tag for the others.
+        let trim = itunes_trim.filter(|_| track.codec_params.delay.is_none());
+        let total_duration = trim
+            .map(|trim| trim.length)
+            .or(track.codec_params.n_frames)
+            .zip(track.codec_params.sample_rate)
+            .map(|(frames, rate)| Duration::from_secs_f64(frames as f64 / rate as f64));
+
+        let mut decoder = make_decoder(&track.codec_params)?;
+
+        // Decode the first packet up front so the signal spec is known before
+        // the source is handed to rodio.
+        let mut decode_errors = 0;
+        let (spec, buffer) = loop {
+            let packet = format.next_packet()?;
+            if packet.track_id() != track_id {
+                continue;
+            }
+            match decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let spec = *decoded.spec();
+                    let mut buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+                    buffer.copy_interleaved_ref(decoded);
+                    break (spec, buffer);
+                }
+                Err(Error::DecodeError(e)) => {
+                    decode_errors += 1;
+                    if decode_errors > MAX_DECODE_ERRORS {
+                        return Err(DecodeError::Decode(e.to_string()));
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let end = buffer.len();
+        let mut track_decoder = TrackDecoder {
+            file_path: file_path.to_string(),
+            format,
+            decoder,
+            track_id,
+            time_base,
+            spec,
+            buffer,
+            offset: 0,
+            end,
+            trim,
+            total_duration,
+            error: None,
+            dropped_packets: decode_errors,
+        };
+        track_decoder.apply_trim();
+        Ok(track_decoder)
+    }
+
+    /// Moves playback to `position` using the container's seek index, landing
+    /// on the exact frame. Streams that can't seek are reopened and decoded up
+    /// to `position` instead, which can take a while, so this is never done
+    /// on the output's thread.
+    pub fn seek(&mut self, position: Duration) -> Result<(), DecodeError> {
+        let rate = self.spec.rate as f64;
+        // The stream's timestamps include the encoder delay.
+        let delay = self.trim.map_or(0.0, |trim| trim.delay as f64 / rate);
+        let target = position.as_secs_f64() + delay;
+        let time = Time::new(target.trunc() as u64, target.fract());
+        let seek_to = SeekTo::Time {
+            time,
+            track_id: Some(self.track_id),
+        };
+        self.buffer.clear();
+        self.offset = 0;
+        self.end = 0;
+        self.error = None;
+
+        match self.format.seek(SeekMode::Accurate, seek_to) {
+            Ok(seeked) => {
+                self.decoder.reset();
+                // The format lands on the packet containing `position`, the
+                // frames in front of it still have to be dropped.
+                let early = seeked.required_ts.saturating_sub(seeked.actual_ts);
+                let frames = match self.time_base {
+                    Some(time_base) => {
+                        let time = time_base.calc_time(early);
+                        ((time.seconds as f64 + time.frac) * rate) as u64
+                    }
+                    None => early,
+                };
+                if let Some(trim) = &mut self.trim {
+                    let played = (position.as_secs_f64() * rate) as u64;
+                    trim.skip = 0;
+                    trim.remaining = trim.length.saturating_sub(played) + frames;
+                }
+                self.skip_frames(frames);
+            }
+            Err(_) => {
+                *self = TrackDecoder::open(&self.file_path)?;
+                self.skip_frames((position.as_secs_f64() * self.spec.rate as f64) as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Why the samples ran out before the end of the track, `None` when they
+    /// didn't or haven't.
+    pub fn error(&self) -> Option<&DecodeError> {
+        self.error.as_ref()
+    }
+
+    /// How many packets were dropped for failing to decode since this was
+    /// last called. Playback skips over them, so this is the only sign.
+    pub fn take_dropped_packets(&mut self) -> usize {
+        std::mem::take(&mut self.dropped_packets)
+    }
+
+    // Decodes and drops `frames` frames.
+    fn skip_frames(&mut self, frames: u64) {
+        let channels = self.spec.channels.count().max(1);
+        let mut samples = frames as usize * channels;
+        loop {
+            let available = self.end - self.offset;
+            if samples <= available {
+                self.offset += samples;
+                return;
+            }
+            samples -= available;
+            if !self.next_buffer() {
+                self.offset = self.end;
+                return;
+            }
+        }
+    }
+
+    // Narrows the freshly decoded buffer down to the frames that are neither
+    // encoder delay nor padding.
+    fn apply_trim(&mut self) {
+        let channels = self.spec.channels.count().max(1);
+        let Some(trim) = &mut self.trim else {
+            return;
+        };
+        let frames = ((self.end - self.offset) / channels) as u64;
+        let skip = trim.skip.min(frames);
+        trim.skip -= skip;
+        let keep = (frames - skip).min(trim.remaining);
+        trim.remaining -= keep;
+        self.offset += skip as usize * channels;
+        self.end = self.offset + keep as usize * channels;
+    }
+
+    fn next_buffer(&mut self) -> bool {
+        if self.trim.is_some_and(|trim| trim.remaining == 0) {
+            return false;
+        }
+        let mut decode_errors = 0;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return false,
+                Err(Error::ResetRequired) => return false,
+                Err(e) => {
+                    self.error = Some(e.into());
+                    return false;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    self.spec = *decoded.spec();
+                    if self.buffer.capacity() < decoded.capacity() * self.spec.channels.count() {
+                        self.buffer = SampleBuffer::new(decoded.capacity() as u64, self.spec);
+                    }
+                    self.buffer.copy_interleaved_ref(decoded);
+                    self.offset = 0;
+                    self.end = self.buffer.len();
+                    self.apply_trim();
+                    return true;
+                }
+                Err(Error::DecodeError(e)) => {
+                    self.dropped_packets += 1;
+                    decode_errors += 1;
+                    if decode_errors > MAX_DECODE_ERRORS {
+                        self.error = Some(DecodeError::Decode(e.to_string()));
+                        return false;
+                    }
+                }
+                Err(e) => {
+                    self.error = Some(e.into());
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for TrackDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // Some packets decode to zero frames, keep going until there is audio.
+        while self.offset >= self.end {
+            if !self.next_buffer() {
+                return None;
+            }
+        }
+        let sample = self.buffer.samples()[self.offset];
+        self.offset += 1;
+        Some(sample)
+    }
+}
+
+impl Source for TrackDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.end - self.offset)
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec.channels.count() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.spec.rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+/// Checks that the codec of a probed file can be decoded, without decoding
+/// any samples.
+pub fn check_codec(probed: &ProbeResult) -> Result<(), DecodeError> {
+    let track = audio_track(probed.format.as_ref()).ok_or(DecodeError::NoAudioTrack)?;
+    make_decoder(&track.codec_params).map(|_| ())
+}
+
+/// Works out the length of a probed file. Most containers store the number
+/// of frames in their header, for the rest (VBR MP3 without a Xing header,
+/// some Ogg files) the packets are walked without decoding them.
+pub fn scan_duration(probed: ProbeResult) -> Option<Duration> {
+    let mut format = probed.format;
+    let track = audio_track(format.as_ref())?;
+    let track_id = track.id;
+    let params = &track.codec_params;
+    if let (Some(frames), Some(rate)) = (params.n_frames, params.sample_rate) {
+        return Some(Duration::from_secs_f64(frames as f64 / rate as f64));
+    }
+    let time_base = params.time_base?;
+
+    let mut end = 0;
+    while let Ok(packet) = format.next_packet() {
+        if packet.track_id() == track_id {
+            end = end.max(packet.ts() + packet.dur());
+        }
+    }
+    let time = time_base.calc_time(end);
+    Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+}
+
+pub fn probe(file_path: &str) -> Result<ProbeResult, DecodeError> {
+    let file = File::open(file_path).map_err(|e| DecodeError::Io(e.to_string()))?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    // An empty hint makes symphonia pick the format from the stream's magic
+    // bytes alone. With gapless enabled it also drops the encoder delay and
+    // padding it knows about.
+    let format_options = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+    symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            mss,
+            &format_options,
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| match e {
+            // Bytes that look like the start of a format marker near the end
+            // of a file that isn't audio
+            Error::IoError(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                DecodeError::UnrecognizedFormat
+            }
+            e => e.into(),
+        })
+}
+
+// iTunes stores the delay and padding of AAC files in an iTunSMPB tag.
+fn read_itunes_trim(probed: &mut ProbeResult) -> Option<Trim> {
+    let find = |revision: &MetadataRevision| {
+        revision
+            .tags()
+            .iter()
+            .find(|tag| tag.key.to_ascii_lowercase().ends_with("itunsmpb"))
+            .and_then(|tag| parse_itunsmpb(&tag.value.to_string()))
+    };
+    if let Some(trim) = probed.format.metadata().current().and_then(find) {
+        return Some(trim);
+    }
+    let mut metadata = probed.metadata.get()?;
+    metadata.skip_to_latest().and_then(find)
+}
+
+// The tag holds hexadecimal fields, " 00000000 00000840 0000017C 0000000000A1B2C3 ...",
+// the second is the delay, the third the padding and the fourth the length
+// of the audio in frames.
+fn parse_itunsmpb(value: &str) -> Option<Trim> {
+    let mut fields = value
+        .split_whitespace()
+        .map(|field| u64::from_str_radix(field, 16).ok());
+    fields.next()?;
+    let delay = fields.next()??;
+    let _padding = fields.next()??;
+    let length = fields.next()??;
+    if length == 0 {
+        return None;
+    }
+    Some(Trim {
+        delay,
+        length,
+        skip: delay,
+        remaining: length,
+    })
+}
+
+fn audio_track(format: &dyn FormatReader) -> Option<&Track> {
+    format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != codecs::CODEC_TYPE_NULL)
+}
+
+// The codecs symphonia decodes, and Opus.
+fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusDecoder>();
+        registry
+    })
+}
+
+fn make_decoder(params: &codecs::CodecParameters) -> Result<Box<dyn codecs::Decoder>, DecodeError> {
+    codecs()
+        .make(params, &DecoderOptions::default())
+        .map_err(|e| match e {
+            Error::Unsupported(_) => DecodeError::UnsupportedCodec(codec_name(params.codec)),
+            e => e.into(),
+        })
+}
+
+fn codec_name(codec: CodecType) -> String {
+    if let Some(descriptor) = codecs().get_codec(codec) {
+        return descriptor.short_name.to_string();
+    }
+    // Codecs without a registered decoder have no descriptor to name them.
+    match codec {
+        codecs::CODEC_TYPE_WMA => "WMA".to_string(),
+        codec => codec.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_sniffs_wav() {
+        let decoder = TrackDecoder::open("assets/tracks/CantinaBand60.wav").unwrap();
+        assert!(decoder.sample_rate() > 0);
+        assert!(decoder.channels() > 0);
+        assert!(decoder.total_duration().unwrap() > Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_ends_without_error() {
+        let mut decoder = TrackDecoder::open("assets/tracks/CantinaBand60.wav").unwrap();
+        assert!(decoder.by_ref().count() > 0);
+        assert_eq!(decoder.error(), None);
+    }
+
+    #[test]
+    fn test_scan_duration() {
+        let probed = probe("assets/tracks/CantinaBand60.wav").unwrap();
+        let duration = scan_duration(probed).unwrap();
+        assert_eq!(duration.as_secs(), 60);
+    }
+
+    #[test]
+    fn test_seek_is_sample_accurate() {
+        let mut straight = TrackDecoder::open("assets/tracks/CantinaBand60.wav").unwrap();
+        let frame = straight.channels() as usize;
+        let skip = straight.sample_rate() as usize * frame * 3 / 2;
+        let expected: Vec<f32> = straight.by_ref().skip(skip).take(100).collect();
+
+        let mut seeked = TrackDecoder::open("assets/tracks/CantinaBand60.wav").unwrap();
+        seeked.seek(Duration::from_millis(1500)).unwrap();
+        let actual: Vec<f32> = seeked.take(100).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_decodes_opus() {
+        let mut decoder = TrackDecoder::open("assets/tracks/CantinaBand2.opus").unwrap();
+        assert_eq!(decoder.sample_rate(), 48_000);
+        assert_eq!(decoder.channels(), 1);
+        // The pre-skip and the padding at the end are trimmed
+        let samples: Vec<f32> = decoder.by_ref().collect();
+        assert_eq!(samples.len(), 96_000);
+        assert!(samples.iter().any(|sample| sample.abs() > 0.01));
+        assert_eq!(decoder.error(), None);
+    }
+
+    #[test]
+    fn test_parse_itunsmpb() {
+        let trim = parse_itunsmpb(" 00000000 00000840 0000017C 00000000001E8480 00000000").unwrap();
+        assert_eq!(trim.delay, 2112);
+        assert_eq!(trim.length, 2_000_000);
+        assert_eq!(parse_itunsmpb(" 00000000 00000840"), None);
+        assert_eq!(parse_itunsmpb("not a number"), None);
+    }
+
+    #[test]
+    fn test_open_rejects_non_audio() {
+        let result = TrackDecoder::open("Cargo.toml");
+        assert_eq!(result.err(), Some(DecodeError::UnrecognizedFormat));
+    }
+}
@@ -0,0 +1,376 @@
+use crate::audio_track::{Track, TrackId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::f32::consts::PI;
+use std::fmt;
+
+/// The centre frequencies of the graphic equalizer, one per octave.
+pub const GRAPHIC_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+// An octave wide, so neighbouring graphic bands overlap smoothly.
+const GRAPHIC_Q: f32 = 1.41;
+
+pub const MAX_GAIN_DB: f32 = 12.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    pub frequency: f32,
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EqMode {
+    /// Fixed octave bands where only the gains are set.
+    Graphic,
+    /// Any number of bands with their own frequency, gain and Q.
+    Parametric,
+}
+
+impl fmt::Display for EqMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EqMode::Graphic => write!(f, "Graphic"),
+            EqMode::Parametric => write!(f, "Parametric"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EqPreset {
+    pub name: String,
+    pub mode: EqMode,
+    /// Applied before the bands, to make room for boosts without clipping.
+    pub preamp_db: f32,
+    pub bands: Vec<Band>,
+}
+
+impl EqPreset {
+    pub fn graphic(name: &str, gains: [f32; 10]) -> EqPreset {
+        let bands = GRAPHIC_FREQUENCIES
+            .iter()
+            .zip(gains)
+            .map(|(&frequency, gain_db)| Band {
+                frequency,
+                gain_db,
+                q: GRAPHIC_Q,
+            })
+            .collect();
+        EqPreset {
+            name: name.to_string(),
+            mode: EqMode::Graphic,
+            preamp_db: 0.0,
+            bands,
+        }
+    }
+
+    /// Switches between modes. Going to graphic mode keeps the gain of the
+    /// band closest to each octave, going to parametric keeps every band.
+    pub fn set_mode(&mut self, mode: EqMode) {
+        if mode == EqMode::Graphic && self.mode != EqMode::Graphic {
+            let mut gains = [0.0; 10];
+            for (gain, &frequency) in gains.iter_mut().zip(&GRAPHIC_FREQUENCIES) {
+                let closest = self.bands.iter().min_by(|a, b| {
+                    let distance = |band: &Band| (band.frequency / frequency).ln().abs();
+                    distance(a).total_cmp(&distance(b))
+                });
+                *gain = closest.map_or(0.0, |band| band.gain_db);
+            }
+            self.bands = EqPreset::graphic(&self.name, gains).bands;
+        }
+        self.mode = mode;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct EqSettings {
+    pub enabled: bool,
+    pub presets: Vec<EqPreset>,
+    /// Used for tracks with no preset of their own or of their genre.
+    pub default_preset: String,
+    pub genre_presets: BTreeMap<String, String>,
+    /// Presets assigned to single tracks.
+    #[serde(rename = "presets_by_track")]
+    pub track_presets: BTreeMap<TrackId, String>,
+    // Presets assigned by file path, as they were saved before tracks had
+    // IDs. They move over to `track_presets` once the library is scanned.
+    #[serde(rename = "track_presets", skip_serializing_if = "BTreeMap::is_empty")]
+    path_presets: BTreeMap<String, String>,
+}
+
+impl Default for EqSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            presets: vec![
+                EqPreset::graphic("Flat", [0.0; 10]),
+                EqPreset::graphic(
+                    "Bass Boost",
+                    [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                ),
+                EqPreset::graphic(
+                    "Treble Boost",
+                    [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 4.0, 5.0, 6.0],
+                ),
+                EqPreset::graphic(
+                    "Vocal",
+                    [-2.0, -2.0, -1.0, 0.0, 2.0, 3.0, 3.0, 2.0, 0.0, -1.0],
+                ),
+                EqPreset::graphic(
+                    "Loudness",
+                    [5.0, 4.0, 2.0, 0.0, -1.0, 0.0, 0.0, 1.0, 3.0, 4.0],
+                ),
+            ],
+            default_preset: "Flat".to_string(),
+            genre_presets: BTreeMap::new(),
+            track_presets: BTreeMap::new(),
+            path_presets: BTreeMap::new(),
+        }
+    }
+}
+
+impl EqSettings {
+    pub fn preset(&self, name: &str) -> Option<&EqPreset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+
+    pub fn preset_mut(&mut self, name: &str) -> Option<&mut EqPreset> {
+        self.presets.iter_mut().find(|preset| preset.name == name)
+    }
+
+    /// The preset for a track: its own, else its genre's, else the default.
+    pub fn preset_for(&self, track: &Track) -> Option<&EqPreset> {
+        let genre_preset = track.genre.as_deref().and_then(|genre| {
+            self.genre_presets
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(genre))
+                .map(|(_, preset)| preset)
+        });
+        track
+            .id
+            .and_then(|id| self.track_presets.get(&id))
+            .or(genre_preset)
+            .and_then(|name| self.preset(name))
+            .or_else(|| self.preset(&self.default_preset))
+    }
+
+    /// Moves presets saved by file path over to the library tracks at those
+    /// paths. Those whose file has left the library are dropped. Returns
+    /// whether there were any.
+    pub fn move_path_presets(&mut self, library_tracks: &[Track]) -> bool {
+        if self.path_presets.is_empty() {
+            return false;
+        }
+        for track in library_tracks {
+            let Some(id) = track.id else {
+                continue;
+            };
+            if let Some(preset) = self.path_presets.remove(&track.file_path) {
+                self.track_presets.entry(id).or_insert(preset);
+            }
+        }
+        self.path_presets.clear();
+        true
+    }
+
+    /// Renames or deletes leave assignments pointing at nothing, drop those.
+    pub fn remove_dangling_assignments(&mut self) {
+        let names: Vec<String> = self.presets.iter().map(|p| p.name.clone()).collect();
+        self.genre_presets
+            .retain(|_, preset| names.contains(preset));
+        self.track_presets
+            .retain(|_, preset| names.contains(preset));
+    }
+}
+
+/// A peaking filter from the Audio EQ Cookbook, in transposed direct form II.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    fn peaking(band: &Band, sample_rate: u32) -> Biquad {
+        let nyquist = sample_rate as f32 / 2.0;
+        let frequency = band.frequency.clamp(10.0, nyquist * 0.95);
+        let a = 10f32.powf(band.gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * band.q.max(0.05));
+        let a0 = 1.0 + alpha / a;
+        Biquad {
+            b0: (1.0 + alpha * a) / a0,
+            b1: -2.0 * w0.cos() / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha / a) / a0,
+        }
+    }
+
+    fn process(&self, state: &mut [f32; 2], input: f32) -> f32 {
+        let output = self.b0 * input + state[0];
+        state[0] = self.b1 * input - self.a1 * output + state[1];
+        state[1] = self.b2 * input - self.a2 * output;
+        output
+    }
+}
+
+/// Runs interleaved frames through the bands of a preset.
+pub struct Equalizer {
+    sample_rate: u32,
+    channels: usize,
+    preamp: f32,
+    filters: Vec<Biquad>,
+    // Two values of filter state per band and channel.
+    states: Vec<[f32; 2]>,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: u32, channels: u16) -> Equalizer {
+        Equalizer {
+            sample_rate,
+            channels: channels.max(1) as usize,
+            preamp: 1.0,
+            filters: Vec::new(),
+            states: Vec::new(),
+        }
+    }
+
+    /// Switches to `preset`, or to no equalization for `None`. The filter
+    /// state is kept where the band count allows, so the switch doesn't click.
+    pub fn set_preset(&mut self, preset: Option<&EqPreset>) {
+        let Some(preset) = preset else {
+            self.preamp = 1.0;
+            self.filters.clear();
+            self.states.clear();
+            return;
+        };
+        self.preamp = 10f32.powf(preset.preamp_db / 20.0);
+        self.filters = preset
+            .bands
+            .iter()
+            // Bands at 0 dB don't change anything
+            .filter(|band| band.gain_db != 0.0)
+            .map(|band| Biquad::peaking(band, self.sample_rate))
+            .collect();
+        self.states
+            .resize(self.filters.len() * self.channels, [0.0; 2]);
+    }
+
+    pub fn process(&mut self, frame: &mut [f32]) {
+        if self.filters.is_empty() && self.preamp == 1.0 {
+            return;
+        }
+        for (channel, sample) in frame.iter_mut().enumerate().take(self.channels) {
+            let mut value = *sample * self.preamp;
+            for (band, filter) in self.filters.iter().enumerate() {
+                value = filter.process(&mut self.states[band * self.channels + channel], value);
+            }
+            *sample = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The peak level of a sine at `frequency` after the equalizer settles.
+    fn sine_peak(equalizer: &mut Equalizer, frequency: f32) -> f32 {
+        let rate = 48000;
+        let mut peak: f32 = 0.0;
+        for i in 0..rate {
+            let mut frame = [(2.0 * PI * frequency * i as f32 / rate as f32).sin()];
+            equalizer.process(&mut frame);
+            if i > rate / 2 {
+                peak = peak.max(frame[0].abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn test_peaking_band_boosts_its_frequency() {
+        let mut preset = EqPreset::graphic("Test", [0.0; 10]);
+        preset.bands[5].gain_db = 6.0; // 1 kHz
+        let mut equalizer = Equalizer::new(48000, 1);
+        equalizer.set_preset(Some(&preset));
+
+        let boosted = sine_peak(&mut equalizer, 1000.0);
+        assert!((boosted - 2.0).abs() < 0.05, "{}", boosted);
+        let untouched = sine_peak(&mut equalizer, 100.0);
+        assert!((untouched - 1.0).abs() < 0.05, "{}", untouched);
+    }
+
+    #[test]
+    fn test_flat_preset_passes_through() {
+        let mut equalizer = Equalizer::new(48000, 2);
+        equalizer.set_preset(Some(&EqPreset::graphic("Flat", [0.0; 10])));
+        let mut frame = [0.25, -0.5];
+        equalizer.process(&mut frame);
+        assert_eq!(frame, [0.25, -0.5]);
+    }
+
+    #[test]
+    fn test_preset_for_prefers_track_then_genre() {
+        let mut settings = EqSettings::default();
+        settings
+            .genre_presets
+            .insert("Rock".to_string(), "Bass Boost".to_string());
+        settings
+            .track_presets
+            .insert(TrackId(1), "Vocal".to_string());
+
+        let name = |id: i64, genre: Option<&str>| {
+            let track = Track {
+                id: Some(TrackId(id)),
+                genre: genre.map(str::to_string),
+                ..Default::default()
+            };
+            settings.preset_for(&track).unwrap().name.clone()
+        };
+        assert_eq!(name(1, Some("rock")), "Vocal");
+        assert_eq!(name(2, Some("rock")), "Bass Boost");
+        assert_eq!(name(2, None), "Flat");
+    }
+
+    #[test]
+    fn test_moves_presets_saved_by_path() {
+        let mut settings = EqSettings::default();
+        settings
+            .path_presets
+            .insert("song.mp3".to_string(), "Vocal".to_string());
+        settings
+            .path_presets
+            .insert("gone.mp3".to_string(), "Bass Boost".to_string());
+        let tracks = [Track {
+            id: Some(TrackId(7)),
+            file_path: "song.mp3".to_string(),
+            ..Default::default()
+        }];
+
+        assert!(settings.move_path_presets(&tracks));
+        assert_eq!(settings.track_presets.get(&TrackId(7)).unwrap(), "Vocal");
+        assert_eq!(settings.track_presets.len(), 1);
+        assert!(!settings.move_path_presets(&tracks));
+    }
+
+    #[test]
+    fn test_set_mode_keeps_gains() {
+        let mut preset = EqPreset::graphic("Test", [0.0; 10]);
+        preset.set_mode(EqMode::Parametric);
+        preset.bands = vec![Band {
+            frequency: 110.0,
+            gain_db: 3.0,
+            q: 0.7,
+        }];
+        preset.set_mode(EqMode::Graphic);
+        assert_eq!(preset.bands.len(), 10);
+        assert!(preset.bands.iter().all(|band| band.gain_db == 3.0));
+    }
+}
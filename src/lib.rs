@@ -2,7 +2,21 @@
 mod app;
 mod audio_thread;
 mod audio_track;
+mod crossfade;
+mod decoder;
+mod equalizer;
+mod library;
+mod library_thread;
+mod loudness;
+mod opus;
+mod output;
+mod player;
 mod playlist;
+mod position;
 mod queue;
+mod speed;
+mod tags;
 mod ui;
+mod visualizer;
+mod waveform;
 pub use app::TemplateApp;
@@ -0,0 +1,988 @@
+use crate::audio_track::{Bookmark, Track, TrackId};
+use crate::loudness::{self, Loudness};
+use crate::waveform::Waveform;
+use glob::Pattern;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+// Each entry upgrades the database from the version before it. The index of
+// the last applied entry + 1 is kept in `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE tracks (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        file_size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT,
+        album TEXT,
+        album_artist TEXT,
+        track_number INTEGER,
+        disc_number INTEGER,
+        year INTEGER,
+        genre TEXT,
+        composer TEXT,
+        duration_ms INTEGER,
+        error TEXT
+    );
+    CREATE TABLE ignored_files (
+        path TEXT PRIMARY KEY,
+        file_size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL
+    );",
+    "ALTER TABLE tracks ADD COLUMN content_hash TEXT;
+    CREATE INDEX tracks_content_hash ON tracks (content_hash);",
+    // Gains read from tags, and the loudness measured for files without them.
+    // Clearing the stamps has the next scan read every file's gain tags.
+    "ALTER TABLE tracks ADD COLUMN track_gain REAL;
+    ALTER TABLE tracks ADD COLUMN track_peak REAL;
+    ALTER TABLE tracks ADD COLUMN album_gain REAL;
+    ALTER TABLE tracks ADD COLUMN album_peak REAL;
+    ALTER TABLE tracks ADD COLUMN loudness REAL;
+    ALTER TABLE tracks ADD COLUMN true_peak REAL;
+    ALTER TABLE tracks ADD COLUMN loudness_analyzed INTEGER NOT NULL DEFAULT 0;
+    UPDATE tracks SET mtime = 0;",
+    // Bookmarks go with their track when it leaves the library.
+    "CREATE TABLE bookmarks (
+        id INTEGER PRIMARY KEY,
+        track_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        position_ms INTEGER NOT NULL
+    );
+    CREATE INDEX bookmarks_track_id ON bookmarks (track_id);
+    CREATE TRIGGER tracks_delete_bookmarks AFTER DELETE ON tracks BEGIN
+        DELETE FROM bookmarks WHERE track_id = old.id;
+    END;",
+    "ALTER TABLE tracks ADD COLUMN resume_ms INTEGER;",
+    // Packed by `Waveform::to_bytes`, empty for files that couldn't be decoded.
+    "ALTER TABLE tracks ADD COLUMN waveform BLOB;",
+];
+
+// How much of each end of a file goes into its content hash.
+const HASH_SAMPLE_SIZE: u64 = 64 * 1024;
+
+const TRACK_COLUMNS: &str = "id, path, title, artist, album, album_artist, track_number,
+    disc_number, year, genre, composer, duration_ms, error, track_gain, track_peak, album_gain,
+    album_peak, loudness, true_peak, resume_ms";
+
+/// Which folders make up the library. Include and exclude patterns are globs
+/// matched against each file's path relative to its root, e.g. `*.flac` or
+/// `Podcasts/**`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct LibrarySettings {
+    pub roots: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Default for LibrarySettings {
+    fn default() -> Self {
+        Self {
+            roots: vec!["assets/tracks".to_string()],
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl LibrarySettings {
+    /// Every file under the roots that passes the include and exclude
+    /// patterns. Symlinks are followed, but a link pointing back up into a
+    /// directory being walked is skipped instead of recursed into forever.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.roots
+            .iter()
+            .flat_map(|root| self.files_below(Path::new(root), Path::new(root)))
+            .collect()
+    }
+
+    /// Maps a path reported by the filesystem watcher, which may be absolute,
+    /// onto the form `files` produces and returns it with its root. `None` if
+    /// the path is outside the roots or inside an excluded folder.
+    pub fn locate(&self, path: &Path) -> Option<(PathBuf, PathBuf)> {
+        let exclude = compile_patterns(&self.exclude);
+        for root in &self.roots {
+            let root = Path::new(root);
+            let candidates = [
+                Some(root.to_path_buf()),
+                std::env::current_dir().ok().map(|dir| dir.join(root)),
+                fs::canonicalize(root).ok(),
+            ];
+            for candidate in candidates.iter().flatten() {
+                if let Ok(relative) = path.strip_prefix(candidate) {
+                    let excluded = relative
+                        .ancestors()
+                        .filter(|a| !a.as_os_str().is_empty())
+                        .any(|a| exclude.iter().any(|p| p.matches_path(a)));
+                    if excluded {
+                        return None;
+                    }
+                    return Some((root.to_path_buf(), root.join(relative)));
+                }
+            }
+        }
+        None
+    }
+
+    /// The files `files` would return that are at or below `start`, which
+    /// must be inside `root`.
+    pub fn files_below(&self, root: &Path, start: &Path) -> Vec<PathBuf> {
+        let include = compile_patterns(&self.include);
+        let exclude = compile_patterns(&self.exclude);
+        let mut files = Vec::new();
+
+        let walker = WalkDir::new(start)
+            .follow_links(true)
+            .into_iter()
+            .filter_entry(|entry| {
+                let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                !exclude.iter().any(|p| p.matches_path(relative))
+            });
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    if e.loop_ancestor().is_some() {
+                        eprintln!("Skipping symlink loop: {}", e);
+                    }
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            if include.is_empty() || include.iter().any(|p| p.matches_path(relative)) {
+                files.push(entry.into_path());
+            }
+        }
+        files
+    }
+}
+
+// Patterns that fail to compile are reported in the settings window and
+// otherwise ignored.
+fn compile_patterns(patterns: &[String]) -> Vec<Pattern> {
+    patterns
+        .iter()
+        .filter_map(|p| Pattern::new(p).ok())
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ScanStats {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    /// Files that turned up under a new path and were matched to the row of
+    /// a file that went missing by their content hash.
+    pub relinked: usize,
+    pub unchanged: usize,
+}
+
+impl ScanStats {
+    pub fn changed(&self) -> bool {
+        self.added + self.updated + self.removed + self.relinked > 0
+    }
+}
+
+/// The on-disk index of every track found under the library roots. Files are
+/// only read again when their size or modification time changes.
+pub struct Library {
+    conn: Connection,
+}
+
+impl Library {
+    pub fn open(path: &Path) -> rusqlite::Result<Library> {
+        Library::migrate(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> rusqlite::Result<Library> {
+        Library::migrate(Connection::open_in_memory()?)
+    }
+
+    fn migrate(conn: Connection) -> rusqlite::Result<Library> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute_batch(migration)?;
+            conn.pragma_update(None, "user_version", i + 1)?;
+        }
+        Ok(Library { conn })
+    }
+
+    pub fn tracks(&self) -> rusqlite::Result<Vec<Track>> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT {} FROM tracks ORDER BY artist, album, disc_number, track_number, title",
+            TRACK_COLUMNS
+        ))?;
+        let mut tracks = statement
+            .query_map([], track_from_row)?
+            .collect::<rusqlite::Result<Vec<Track>>>()?;
+        fill_album_gains(&mut tracks);
+        self.attach_bookmarks(&mut tracks)?;
+        Ok(tracks)
+    }
+
+    pub fn add_bookmark(
+        &self,
+        track: TrackId,
+        name: &str,
+        position: Duration,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO bookmarks (track_id, name, position_ms) VALUES (?1, ?2, ?3)",
+            params![track.0, name, position.as_millis() as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_bookmark(&self, id: i64) -> rusqlite::Result<()> {
+        self.conn
+            .execute("DELETE FROM bookmarks WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn attach_bookmarks(&self, tracks: &mut [Track]) -> rusqlite::Result<()> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, track_id, name, position_ms FROM bookmarks ORDER BY position_ms, id",
+        )?;
+        let mut bookmarks: HashMap<TrackId, Vec<Bookmark>> = HashMap::new();
+        let rows = statement.query_map([], |row| {
+            let bookmark = Bookmark {
+                id: row.get(0)?,
+                name: row.get(2)?,
+                position: Duration::from_millis(row.get::<_, i64>(3)? as u64),
+            };
+            Ok((TrackId(row.get(1)?), bookmark))
+        })?;
+        for row in rows {
+            let (track, bookmark) = row?;
+            bookmarks.entry(track).or_default().push(bookmark);
+        }
+        for track in tracks {
+            if let Some(id) = track.id {
+                track.bookmarks = bookmarks.remove(&id).unwrap_or_default();
+            }
+        }
+        Ok(())
+    }
+
+    /// A track that has no gain from its tags and hasn't been analyzed yet.
+    pub fn next_unanalyzed(&self) -> rusqlite::Result<Option<(TrackId, String)>> {
+        self.conn
+            .query_row(
+                "SELECT id, path FROM tracks
+                 WHERE track_gain IS NULL AND loudness_analyzed = 0 AND error IS NULL
+                 LIMIT 1",
+                [],
+                |row| Ok((TrackId(row.get(0)?), row.get(1)?)),
+            )
+            .optional()
+    }
+
+    /// Stores the measured loudness of a track, or `None` if it couldn't be
+    /// measured, so it isn't analyzed again either way.
+    pub fn store_loudness(&self, id: TrackId, loudness: Option<&Loudness>) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE tracks SET loudness = ?1, true_peak = ?2, loudness_analyzed = 1
+             WHERE id = ?3",
+            params![
+                loudness.and_then(|l| l.integrated),
+                loudness.map(|l| l.true_peak),
+                id.0
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// A track whose waveform hasn't been drawn yet.
+    pub fn next_without_waveform(&self) -> rusqlite::Result<Option<TrackId>> {
+        self.conn
+            .query_row(
+                "SELECT id FROM tracks WHERE waveform IS NULL AND error IS NULL LIMIT 1",
+                [],
+                |row| Ok(TrackId(row.get(0)?)),
+            )
+            .optional()
+    }
+
+    pub fn track_path(&self, id: TrackId) -> rusqlite::Result<Option<String>> {
+        self.conn
+            .query_row("SELECT path FROM tracks WHERE id = ?1", [id.0], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+
+    /// Stores the waveform of a track, or `None` if it couldn't be drawn, so
+    /// it isn't tried again either way.
+    pub fn store_waveform(&self, id: TrackId, waveform: Option<&Waveform>) -> rusqlite::Result<()> {
+        let bytes = waveform.map(Waveform::to_bytes).unwrap_or_default();
+        self.conn.execute(
+            "UPDATE tracks SET waveform = ?1 WHERE id = ?2",
+            params![bytes, id.0],
+        )?;
+        Ok(())
+    }
+
+    /// The stored waveform of a track. `Ok(None)` if it hasn't been drawn
+    /// yet, an empty waveform if it couldn't be.
+    pub fn waveform(&self, id: TrackId) -> rusqlite::Result<Option<Waveform>> {
+        let bytes: Option<Option<Vec<u8>>> = self
+            .conn
+            .query_row("SELECT waveform FROM tracks WHERE id = ?1", [id.0], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(bytes.flatten().map(|bytes| Waveform::from_bytes(&bytes)))
+    }
+
+    /// Remembers where a track was left, `None` to have it start over.
+    pub fn store_resume_position(
+        &self,
+        id: TrackId,
+        position: Option<Duration>,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE tracks SET resume_ms = ?1 WHERE id = ?2",
+            params![position.map(|p| p.as_millis() as i64), id.0],
+        )?;
+        Ok(())
+    }
+
+    /// Brings the index in line with the files the settings select. New and
+    /// modified files are read, files that disappeared or no longer match are
+    /// dropped and everything else is left untouched.
+    pub fn scan(&mut self, settings: &LibrarySettings) -> rusqlite::Result<ScanStats> {
+        let mut stats = ScanStats::default();
+        let mut known = self.known_files()?;
+
+        let tx = self.conn.transaction()?;
+        for path in settings.files() {
+            let previous = known.remove(path.to_string_lossy().as_ref());
+            index_file(&tx, &path, previous, &mut stats)?;
+        }
+
+        // Whatever wasn't seen on disk has been deleted.
+        for path in known.keys() {
+            stats.removed += tx.execute("DELETE FROM tracks WHERE path = ?1", [path])?;
+            tx.execute("DELETE FROM ignored_files WHERE path = ?1", [path])?;
+        }
+        tx.commit()?;
+
+        Ok(stats)
+    }
+
+    /// Applies a batch of paths reported by the filesystem watcher. Each path
+    /// is either a file or folder that now exists and gets (re)indexed, or one
+    /// that is gone and gets dropped along with everything below it.
+    pub fn update_paths(
+        &mut self,
+        paths: &[PathBuf],
+        settings: &LibrarySettings,
+    ) -> rusqlite::Result<ScanStats> {
+        let mut stats = ScanStats::default();
+        let mut gone = Vec::new();
+
+        let tx = self.conn.transaction()?;
+        // New files are indexed before anything is dropped, so a file moved
+        // within the library is relinked rather than removed and re-added.
+        for path in paths {
+            let (root, path) = match settings.locate(path) {
+                Some(located) => located,
+                None => continue,
+            };
+            if !path.exists() {
+                gone.push(path);
+                continue;
+            }
+            for file in settings.files_below(&root, &path) {
+                let previous = known_stamp(&tx, &file)?;
+                index_file(&tx, &file, previous, &mut stats)?;
+            }
+        }
+        for path in gone {
+            // Joining an empty name adds the platform's separator
+            let below = path.join("").to_string_lossy().to_string();
+            let path = path.to_string_lossy().to_string();
+            stats.removed += tx.execute(
+                "DELETE FROM tracks WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+                params![path, below],
+            )?;
+            tx.execute(
+                "DELETE FROM ignored_files WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+                params![path, below],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(stats)
+    }
+
+    fn known_files(&self) -> rusqlite::Result<HashMap<String, (i64, i64)>> {
+        let mut statement = self.conn.prepare(
+            "SELECT path, file_size, mtime FROM tracks
+             UNION ALL SELECT path, file_size, mtime FROM ignored_files",
+        )?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?;
+        rows.collect()
+    }
+}
+
+// Reads a file into the index unless its stamp matches `previous`, the stamp
+// stored for it last time.
+fn index_file(
+    conn: &Connection,
+    path: &Path,
+    previous: Option<(i64, i64)>,
+    stats: &mut ScanStats,
+) -> rusqlite::Result<()> {
+    let (file_size, mtime) = match file_stamp(path) {
+        Some(stamp) => stamp,
+        None => return Ok(()),
+    };
+    if previous == Some((file_size, mtime)) {
+        stats.unchanged += 1;
+        return Ok(());
+    }
+    let file_path = path.to_string_lossy().to_string();
+
+    let track = match Track::new(file_path.clone()) {
+        Ok(track) => track,
+        // Not audio, remember it so it isn't probed again next time.
+        Err(_) => {
+            conn.execute("DELETE FROM tracks WHERE path = ?1", [&file_path])?;
+            conn.execute(
+                "INSERT OR REPLACE INTO ignored_files (path, file_size, mtime)
+                 VALUES (?1, ?2, ?3)",
+                params![file_path, file_size, mtime],
+            )?;
+            return Ok(());
+        }
+    };
+    conn.execute("DELETE FROM ignored_files WHERE path = ?1", [&file_path])?;
+
+    let hash = content_hash(path);
+    if previous.is_some() {
+        stats.updated += 1;
+    } else if let Some(id) = relink_candidate(conn, hash.as_deref())? {
+        conn.execute(
+            "UPDATE tracks SET path = ?1 WHERE id = ?2",
+            params![file_path, id],
+        )?;
+        stats.relinked += 1;
+    } else {
+        stats.added += 1;
+    }
+    upsert_track(conn, &track, file_size, mtime, hash.as_deref())
+}
+
+// A row with the same content whose file no longer exists, i.e. the file that
+// was moved to the path being indexed.
+fn relink_candidate(conn: &Connection, hash: Option<&str>) -> rusqlite::Result<Option<i64>> {
+    let hash = match hash {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+    let mut statement = conn.prepare("SELECT id, path FROM tracks WHERE content_hash = ?1")?;
+    let rows = statement.query_map([hash], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+    for row in rows {
+        let (id, path) = row?;
+        if !Path::new(&path).exists() {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
+
+fn known_stamp(conn: &Connection, path: &Path) -> rusqlite::Result<Option<(i64, i64)>> {
+    conn.query_row(
+        "SELECT file_size, mtime FROM tracks WHERE path = ?1
+         UNION ALL SELECT file_size, mtime FROM ignored_files WHERE path = ?1",
+        [path.to_string_lossy()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+fn upsert_track(
+    conn: &Connection,
+    track: &Track,
+    file_size: i64,
+    mtime: i64,
+    content_hash: Option<&str>,
+) -> rusqlite::Result<()> {
+    // Updating in place instead of replacing keeps the row id, and with it the
+    // track's identity, stable across rescans.
+    conn.execute(
+        "INSERT INTO tracks (path, file_size, mtime, title, artist, album, album_artist,
+            track_number, disc_number, year, genre, composer, duration_ms, error, content_hash,
+            track_gain, track_peak, album_gain, album_peak)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+            ?18, ?19)
+         ON CONFLICT(path) DO UPDATE SET
+            file_size = excluded.file_size,
+            mtime = excluded.mtime,
+            title = excluded.title,
+            artist = excluded.artist,
+            album = excluded.album,
+            album_artist = excluded.album_artist,
+            track_number = excluded.track_number,
+            disc_number = excluded.disc_number,
+            year = excluded.year,
+            genre = excluded.genre,
+            composer = excluded.composer,
+            duration_ms = excluded.duration_ms,
+            error = excluded.error,
+            track_gain = excluded.track_gain,
+            track_peak = excluded.track_peak,
+            album_gain = excluded.album_gain,
+            album_peak = excluded.album_peak,
+            -- The measured loudness only holds for the content it was measured on.
+            loudness = iif(content_hash IS excluded.content_hash, loudness, NULL),
+            true_peak = iif(content_hash IS excluded.content_hash, true_peak, NULL),
+            loudness_analyzed = loudness_analyzed AND content_hash IS excluded.content_hash,
+            resume_ms = iif(content_hash IS excluded.content_hash, resume_ms, NULL),
+            waveform = iif(content_hash IS excluded.content_hash, waveform, NULL),
+            content_hash = excluded.content_hash",
+        params![
+            track.file_path,
+            file_size,
+            mtime,
+            track.title,
+            track.artist,
+            track.album,
+            track.album_artist,
+            track.track_number,
+            track.disc_number,
+            track.year,
+            track.genre,
+            track.composer,
+            track.duration.map(|d| d.as_millis() as i64),
+            track.error,
+            content_hash,
+            track.track_gain,
+            track.track_peak,
+            track.album_gain,
+            track.album_peak,
+        ],
+    )?;
+    Ok(())
+}
+
+fn track_from_row(row: &Row<'_>) -> rusqlite::Result<Track> {
+    let duration_ms: Option<i64> = row.get("duration_ms")?;
+    let resume_ms: Option<i64> = row.get("resume_ms")?;
+    // Gain tags win over the measured loudness.
+    let loudness: Option<f32> = row.get("loudness")?;
+    let true_peak: Option<f32> = row.get("true_peak")?;
+    let track_gain: Option<f32> = row.get("track_gain")?;
+    let track_peak: Option<f32> = row.get("track_peak")?;
+    Ok(Track {
+        id: Some(TrackId(row.get("id")?)),
+        file_path: row.get("path")?,
+        title: row.get("title")?,
+        artist: row.get("artist")?,
+        album: row.get("album")?,
+        album_artist: row.get("album_artist")?,
+        track_number: row.get("track_number")?,
+        disc_number: row.get("disc_number")?,
+        year: row.get("year")?,
+        genre: row.get("genre")?,
+        composer: row.get("composer")?,
+        duration: duration_ms.map(|ms| Duration::from_millis(ms as u64)),
+        track_progress: resume_ms.map(|ms| Duration::from_millis(ms as u64)),
+        error: row.get("error")?,
+        track_gain: track_gain.or(loudness.map(loudness::gain_for)),
+        track_peak: track_peak.or(true_peak),
+        album_gain: row.get("album_gain")?,
+        album_peak: row.get("album_peak")?,
+        ..Default::default()
+    })
+}
+
+// Works out the album gain of albums without album gain tags from the gains
+// of their tracks, once every track of the album has one.
+fn fill_album_gains(tracks: &mut [Track]) {
+    let mut albums: HashMap<(Option<&str>, &str), Vec<usize>> = HashMap::new();
+    for (index, track) in tracks.iter().enumerate() {
+        if let (Some(album), None) = (&track.album, track.album_gain) {
+            let artist = track.album_artist.as_ref().or(track.artist.as_ref());
+            albums
+                .entry((artist.map(String::as_str), album.as_str()))
+                .or_default()
+                .push(index);
+        }
+    }
+    let albums: Vec<Vec<usize>> = albums.into_values().collect();
+
+    for indices in albums {
+        let parts: Option<Vec<(f32, Duration)>> = indices
+            .iter()
+            .map(|&i| {
+                let loudness = loudness::REFERENCE_LUFS - tracks[i].track_gain?;
+                Some((loudness, tracks[i].duration?))
+            })
+            .collect();
+        let Some(album_loudness) = parts.and_then(|parts| loudness::combined_loudness(&parts))
+        else {
+            continue;
+        };
+        let album_peak = indices
+            .iter()
+            .filter_map(|&i| tracks[i].track_peak)
+            .reduce(f32::max);
+        for &i in &indices {
+            tracks[i].album_gain = Some(loudness::gain_for(album_loudness));
+            tracks[i].album_peak = album_peak;
+        }
+    }
+}
+
+// Size and modification time, used to tell whether a file needs reading again.
+fn file_stamp(path: &Path) -> Option<(i64, i64)> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as i64);
+    Some((metadata.len() as i64, mtime))
+}
+
+// Identifies a file by its size and its first and last 64 KiB, which is
+// enough to recognise a moved file without reading all of it.
+fn content_hash(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&size.to_le_bytes());
+
+    let mut buffer = Vec::new();
+    (&mut file)
+        .take(HASH_SAMPLE_SIZE)
+        .read_to_end(&mut buffer)
+        .ok()?;
+    if size > HASH_SAMPLE_SIZE {
+        let tail_start = size.saturating_sub(HASH_SAMPLE_SIZE).max(HASH_SAMPLE_SIZE);
+        file.seek(SeekFrom::Start(tail_start)).ok()?;
+        file.read_to_end(&mut buffer).ok()?;
+    }
+    hasher.update(&buffer);
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+/// Where the library index lives, next to the rest of the app's data.
+pub fn database_path() -> Option<PathBuf> {
+    let dirs = directories_next::ProjectDirs::from("", "", "Rustify")?;
+    Some(dirs.data_dir().join("library.sqlite3"))
+}
+
+/// Points tracks held outside the library, in playlists and the queue, at
+/// their current library entry. Tracks that were moved pick up their new path,
+/// tracks whose file is gone are marked missing.
+pub fn relink_tracks<'a>(
+    tracks: impl IntoIterator<Item = &'a mut Track>,
+    library_tracks: &[Track],
+) {
+    let by_id: HashMap<TrackId, &Track> = library_tracks
+        .iter()
+        .filter_map(|t| t.id.map(|id| (id, t)))
+        .collect();
+    let by_path: HashMap<&str, &Track> = library_tracks
+        .iter()
+        .map(|t| (t.file_path.as_str(), t))
+        .collect();
+
+    for track in tracks {
+        let found = track
+            .id
+            .and_then(|id| by_id.get(&id))
+            .or_else(|| by_path.get(track.file_path.as_str()));
+        match found {
+            Some(library_track) => *track = (*library_track).clone(),
+            None => track.missing = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_is_incremental() {
+        let mut library = Library::open_in_memory().unwrap();
+        let settings = LibrarySettings::default();
+
+        let first = library.scan(&settings).unwrap();
+        assert!(first.added > 0);
+        assert_eq!(first.unchanged, 0);
+
+        let second = library.scan(&settings).unwrap();
+        assert!(!second.changed());
+        assert_eq!(second.unchanged, first.added);
+    }
+
+    #[test]
+    fn test_scan_keeps_track_ids() {
+        let mut library = Library::open_in_memory().unwrap();
+        library.scan(&LibrarySettings::default()).unwrap();
+        let before = library.tracks().unwrap();
+
+        // Forget the stamps so every file is read again.
+        library
+            .conn
+            .execute("UPDATE tracks SET mtime = 0", [])
+            .unwrap();
+        let stats = library.scan(&LibrarySettings::default()).unwrap();
+        assert_eq!(stats.updated, before.len());
+
+        let after = library.tracks().unwrap();
+        let ids = |tracks: &[Track]| tracks.iter().map(|t| t.id).collect::<Vec<_>>();
+        assert_eq!(ids(&before), ids(&after));
+    }
+
+    #[test]
+    fn test_scan_removes_missing_files() {
+        let mut library = Library::open_in_memory().unwrap();
+        library.scan(&LibrarySettings::default()).unwrap();
+        let count = library.tracks().unwrap().len();
+
+        let settings = LibrarySettings {
+            roots: vec!["assets/no-such-dir".to_string()],
+            ..Default::default()
+        };
+        let stats = library.scan(&settings).unwrap();
+        assert_eq!(stats.removed, count);
+        assert!(library.tracks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_settings_patterns() {
+        let all = LibrarySettings::default().files();
+        assert!(all.iter().any(|f| f.ends_with("CantinaBand60.wav")));
+
+        let settings = LibrarySettings {
+            include: vec!["filler*".to_string()],
+            exclude: vec!["filler1*.wav".to_string()],
+            ..Default::default()
+        };
+        let files = settings.files();
+        assert!(!files.is_empty());
+        for file in &files {
+            let name = file.file_name().unwrap().to_string_lossy();
+            assert!(name.starts_with("filler"));
+            assert!(!name.starts_with("filler1"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_files_survives_symlink_loop() {
+        let root = std::env::temp_dir().join(format!("rustify-loop-{}", std::process::id()));
+        let nested = root.join("nested");
+        fs::create_dir_all(&nested).unwrap();
+        fs::copy("assets/tracks/FirstDrums.wav", nested.join("drums.wav")).unwrap();
+        let _ = std::os::unix::fs::symlink(&root, nested.join("back-to-root"));
+
+        let settings = LibrarySettings {
+            roots: vec![root.to_string_lossy().to_string()],
+            ..Default::default()
+        };
+        let files = settings.files();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with("nested/drums.wav"));
+    }
+
+    #[test]
+    fn test_update_paths_relinks_moved_files() {
+        let dir = std::env::temp_dir().join(format!("rustify-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let old_path = dir.join("drums.wav");
+        fs::copy("assets/tracks/FirstDrums.wav", &old_path).unwrap();
+
+        let settings = LibrarySettings {
+            roots: vec![dir.to_string_lossy().to_string()],
+            ..Default::default()
+        };
+        let mut library = Library::open_in_memory().unwrap();
+        library.scan(&settings).unwrap();
+        let before = library.tracks().unwrap();
+
+        let new_path = dir.join("renamed.wav");
+        fs::rename(&old_path, &new_path).unwrap();
+        let stats = library
+            .update_paths(&[old_path, new_path.clone()], &settings)
+            .unwrap();
+        let after = library.tracks().unwrap();
+
+        fs::remove_file(&new_path).unwrap();
+        let removed = library
+            .update_paths(std::slice::from_ref(&new_path), &settings)
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(stats.relinked, 1);
+        assert_eq!(stats.removed, 0);
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].id, before[0].id);
+        assert_eq!(after[0].file_path, new_path.to_string_lossy());
+        assert_eq!(removed.removed, 1);
+        assert!(library.tracks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bookmarks_follow_their_track() {
+        let mut library = Library::open_in_memory().unwrap();
+        library.scan(&LibrarySettings::default()).unwrap();
+        let id = library.tracks().unwrap()[0].id.unwrap();
+
+        library
+            .add_bookmark(id, "Chorus", Duration::from_secs(30))
+            .unwrap();
+        library
+            .add_bookmark(id, "Intro", Duration::from_secs(2))
+            .unwrap();
+        let tracks = library.tracks().unwrap();
+        let names: Vec<&str> = tracks[0]
+            .bookmarks
+            .iter()
+            .map(|b| b.name.as_str())
+            .collect();
+        assert_eq!(names, ["Intro", "Chorus"]);
+        assert!(tracks[1].bookmarks.is_empty());
+
+        library.remove_bookmark(tracks[0].bookmarks[0].id).unwrap();
+        assert_eq!(library.tracks().unwrap()[0].bookmarks.len(), 1);
+
+        // Removing the track takes its bookmarks along
+        library
+            .conn
+            .execute("DELETE FROM tracks WHERE id = ?1", [id.0])
+            .unwrap();
+        let count: i64 = library
+            .conn
+            .query_row("SELECT count(*) FROM bookmarks", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_resume_position_survives_rescan() {
+        let mut library = Library::open_in_memory().unwrap();
+        library.scan(&LibrarySettings::default()).unwrap();
+        let id = library.tracks().unwrap()[0].id.unwrap();
+
+        library
+            .store_resume_position(id, Some(Duration::from_secs(42)))
+            .unwrap();
+        library.scan(&LibrarySettings::default()).unwrap();
+        let tracks = library.tracks().unwrap();
+        assert_eq!(tracks[0].track_progress, Some(Duration::from_secs(42)));
+        assert!(tracks[1].track_progress.is_none());
+
+        library.store_resume_position(id, None).unwrap();
+        assert!(library.tracks().unwrap()[0].track_progress.is_none());
+    }
+
+    #[test]
+    fn test_waveforms_are_drawn_once() {
+        let mut library = Library::open_in_memory().unwrap();
+        library.scan(&LibrarySettings::default()).unwrap();
+        let id = library.next_without_waveform().unwrap().unwrap();
+        assert_eq!(library.waveform(id).unwrap(), None);
+
+        let waveform = Waveform {
+            columns: vec![[-1.0, 1.0], [0.0, 0.0]],
+        };
+        library.store_waveform(id, Some(&waveform)).unwrap();
+        assert_eq!(library.waveform(id).unwrap(), Some(waveform));
+
+        // Files that can't be drawn aren't tried again
+        let next = library.next_without_waveform().unwrap().unwrap();
+        assert_ne!(next, id);
+        library.store_waveform(next, None).unwrap();
+        assert_eq!(library.waveform(next).unwrap(), Some(Waveform::default()));
+        assert_ne!(library.next_without_waveform().unwrap(), Some(next));
+    }
+
+    #[test]
+    fn test_relink_tracks_marks_missing() {
+        let mut library = Library::open_in_memory().unwrap();
+        library.scan(&LibrarySettings::default()).unwrap();
+        let library_tracks = library.tracks().unwrap();
+
+        let mut moved = library_tracks[0].clone();
+        moved.file_path = "somewhere/else.wav".to_string();
+        let gone = Track {
+            file_path: "assets/tracks/deleted.wav".to_string(),
+            ..Default::default()
+        };
+        let mut tracks = vec![moved, gone];
+        relink_tracks(&mut tracks, &library_tracks);
+
+        assert_eq!(tracks[0].file_path, library_tracks[0].file_path);
+        assert!(!tracks[0].missing);
+        assert!(tracks[1].missing);
+    }
+
+    #[test]
+    fn test_analyzed_loudness_gives_gains() {
+        let mut library = Library::open_in_memory().unwrap();
+        let settings = LibrarySettings {
+            include: vec!["CantinaBand60.wav".to_string()],
+            ..Default::default()
+        };
+        library.scan(&settings).unwrap();
+        assert!(library.tracks().unwrap()[0].track_gain.is_none());
+
+        let (id, path) = library.next_unanalyzed().unwrap().unwrap();
+        let loudness = loudness::analyze(&path).unwrap();
+        library.store_loudness(id, Some(&loudness)).unwrap();
+        assert_eq!(library.next_unanalyzed().unwrap(), None);
+
+        let track = &library.tracks().unwrap()[0];
+        let expected = loudness::gain_for(loudness.integrated.unwrap());
+        assert_eq!(track.track_gain, Some(expected));
+        assert_eq!(track.track_peak, Some(loudness.true_peak));
+    }
+
+    #[test]
+    fn test_fill_album_gains() {
+        let track = |gain, duration| Track {
+            album: Some("Album".to_string()),
+            track_gain: Some(gain),
+            track_peak: Some(0.5),
+            duration: Some(Duration::from_secs(duration)),
+            ..Default::default()
+        };
+        let mut tracks = vec![track(-4.0, 100), track(-8.0, 100)];
+        fill_album_gains(&mut tracks);
+        let album_gain = tracks[0].album_gain.unwrap();
+        assert!(album_gain < -5.0 && album_gain > -8.0, "{}", album_gain);
+        assert_eq!(tracks[1].album_gain, Some(album_gain));
+        assert_eq!(tracks[1].album_peak, Some(0.5));
+
+        // Not every track of the album has been analyzed yet
+        tracks.push(Track {
+            track_gain: None,
+            ..track(0.0, 100)
+        });
+        tracks.iter_mut().for_each(|t| t.album_gain = None);
+        fill_album_gains(&mut tracks);
+        assert!(tracks.iter().all(|t| t.album_gain.is_none()));
+    }
+}
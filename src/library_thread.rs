@@ -0,0 +1,375 @@
+use crate::audio_track::{Track, TrackId};
+use crate::decoder::DecodeError;
+use crate::library::{self, Library, LibrarySettings};
+use crate::loudness::{self, Loudness};
+use crate::waveform::{self, Waveform};
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+// Watcher events are collected until the filesystem has been quiet for this
+// long, so a file that is still being copied in is only read once.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Tracks are published again after this many have had their loudness
+// measured, so gains show up without resending the library for every track.
+const ANALYSIS_BATCH: usize = 25;
+
+pub enum LibraryCommand {
+    Rescan(LibrarySettings),
+    /// Sent by the filesystem watcher with the paths an event touched.
+    FilesChanged(Vec<PathBuf>),
+    AddBookmark {
+        track: TrackId,
+        name: String,
+        position: Duration,
+    },
+    RemoveBookmark(i64),
+    /// Remembers where a track was left, `None` to have it start over.
+    SaveResumePosition {
+        track: TrackId,
+        position: Option<Duration>,
+    },
+    /// Asks for the waveform of a track. One that hasn't been drawn yet is
+    /// drawn before any other and sent once it is.
+    LoadWaveform(TrackId),
+    /// Sent by the analysis thread with the loudness of a track it measured.
+    Analyzed {
+        track: TrackId,
+        loudness: Result<Loudness, DecodeError>,
+    },
+    /// Sent by the analysis thread with the waveform of a track it drew.
+    Drawn {
+        track: TrackId,
+        waveform: Result<Waveform, DecodeError>,
+    },
+}
+
+pub enum LibraryEvent {
+    /// The full, sorted list of tracks in the library after a change.
+    TracksChanged(Vec<Track>),
+    /// A rescan went through, after the `TracksChanged` it led to if any.
+    ScanFinished,
+    /// The waveform asked for with `LoadWaveform`. Empty if the track
+    /// couldn't be drawn.
+    Waveform(TrackId, Waveform),
+    /// Where a track resumes was saved, to the millisecond. Sent instead of
+    /// the whole library, as this changes every time playback stops.
+    ResumePositionSaved {
+        track: TrackId,
+        position: Option<Duration>,
+    },
+    /// A track's loudness couldn't be measured, so it isn't normalized.
+    AnalysisFailed { track: TrackId, error: DecodeError },
+}
+
+/// Spawns the thread that owns the library database. It publishes the stored
+/// tracks straight away and again after every rescan or watched change that
+/// touched the library. Tracks are decoded one at a time on a thread of their
+/// own, so a long file never holds up commands: first the waveforms asked
+/// for, then the loudness of tracks that have no gain tags, then the
+/// waveforms of tracks that have none yet.
+pub fn create_library_thread() -> (Sender<LibraryCommand>, Receiver<LibraryEvent>) {
+    let (command_sender, command_receiver) = mpsc::channel();
+    let (event_sender, event_receiver) = mpsc::channel();
+    let watch_sender = command_sender.clone();
+    let analysis_sender = spawn_analysis_thread(command_sender.clone());
+
+    thread::Builder::new()
+        .name("Library Thread".to_string())
+        .spawn(move || {
+            let opened = match library::database_path() {
+                Some(path) => {
+                    if let Some(dir) = path.parent() {
+                        let _ = fs::create_dir_all(dir);
+                    }
+                    Library::open(&path)
+                }
+                None => Library::open_in_memory(),
+            };
+            let mut library = match opened {
+                Ok(library) => library,
+                Err(e) => {
+                    eprintln!("Error opening library: {}", e);
+                    return;
+                }
+            };
+
+            let publish = |library: &Library| match library.tracks() {
+                Ok(tracks) => {
+                    let _ = event_sender.send(LibraryEvent::TracksChanged(tracks));
+                }
+                Err(e) => eprintln!("Error reading library: {}", e),
+            };
+
+            publish(&library);
+
+            let mut settings: Option<LibrarySettings> = None;
+            // Kept alive for as long as the roots it watches are current.
+            let mut _watcher: Option<RecommendedWatcher> = None;
+            let mut pending: HashSet<PathBuf> = HashSet::new();
+            let mut work = Work {
+                analyzing: true,
+                drawing: true,
+                wanted: None,
+            };
+            // Whether the analysis thread is busy with a track.
+            let mut busy = false;
+            // The waveform asked for with `LoadWaveform`, until it is sent.
+            let mut asked: Option<TrackId> = None;
+            let mut analyzed = 0;
+
+            loop {
+                if !busy {
+                    if let Some(job) = work.next_job(&library) {
+                        busy = analysis_sender.send(job).is_ok();
+                    }
+                    if !work.analyzing && analyzed > 0 {
+                        publish(&library);
+                        analyzed = 0;
+                    }
+                }
+
+                let command = if !pending.is_empty() {
+                    match command_receiver.recv_timeout(WATCH_DEBOUNCE) {
+                        Ok(command) => command,
+                        Err(RecvTimeoutError::Timeout) => {
+                            let paths: Vec<PathBuf> = pending.drain().collect();
+                            if let Some(settings) = &settings {
+                                match library.update_paths(&paths, settings) {
+                                    Ok(stats) if stats.changed() => {
+                                        publish(&library);
+                                        work.analyzing = true;
+                                        work.drawing = true;
+                                    }
+                                    Ok(_) => (),
+                                    Err(e) => eprintln!("Error updating library: {}", e),
+                                }
+                            }
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                } else {
+                    match command_receiver.recv() {
+                        Ok(command) => command,
+                        Err(_) => break,
+                    }
+                };
+
+                match command {
+                    LibraryCommand::Rescan(new_settings) => {
+                        _watcher = watch_roots(&new_settings, watch_sender.clone());
+                        // The scan covers anything the watcher had queued up.
+                        pending.clear();
+                        match library.scan(&new_settings) {
+                            Ok(stats) => {
+                                if stats.changed() {
+                                    publish(&library);
+                                    work.analyzing = true;
+                                    work.drawing = true;
+                                }
+                                let _ = event_sender.send(LibraryEvent::ScanFinished);
+                            }
+                            Err(e) => eprintln!("Error scanning library: {}", e),
+                        }
+                        settings = Some(new_settings);
+                    }
+                    LibraryCommand::FilesChanged(paths) => pending.extend(paths),
+                    LibraryCommand::AddBookmark {
+                        track,
+                        name,
+                        position,
+                    } => match library.add_bookmark(track, &name, position) {
+                        Ok(()) => publish(&library),
+                        Err(e) => eprintln!("Error adding bookmark: {}", e),
+                    },
+                    LibraryCommand::RemoveBookmark(id) => match library.remove_bookmark(id) {
+                        Ok(()) => publish(&library),
+                        Err(e) => eprintln!("Error removing bookmark: {}", e),
+                    },
+                    LibraryCommand::SaveResumePosition { track, position } => {
+                        match library.store_resume_position(track, position) {
+                            Ok(()) => {
+                                let position = position.map(|position| {
+                                    Duration::from_millis(position.as_millis() as u64)
+                                });
+                                let event = LibraryEvent::ResumePositionSaved { track, position };
+                                let _ = event_sender.send(event);
+                            }
+                            Err(e) => eprintln!("Error saving resume position: {}", e),
+                        }
+                    }
+                    LibraryCommand::LoadWaveform(track) => match library.waveform(track) {
+                        Ok(Some(waveform)) => {
+                            let _ = event_sender.send(LibraryEvent::Waveform(track, waveform));
+                        }
+                        Ok(None) => {
+                            asked = Some(track);
+                            work.wanted = Some(track);
+                        }
+                        Err(e) => eprintln!("Error reading library: {}", e),
+                    },
+                    LibraryCommand::Drawn { track, waveform } => {
+                        busy = false;
+                        // A track that can't be drawn can't be measured
+                        // either, that is where it gets reported
+                        let drawn = waveform.ok();
+                        if let Err(e) = library.store_waveform(track, drawn.as_ref()) {
+                            eprintln!("Error updating library: {}", e);
+                            work.drawing = false;
+                        }
+                        if asked == Some(track) {
+                            asked = None;
+                            let waveform = drawn.unwrap_or_default();
+                            let _ = event_sender.send(LibraryEvent::Waveform(track, waveform));
+                        }
+                    }
+                    LibraryCommand::Analyzed { track, loudness } => {
+                        busy = false;
+                        let measured = match loudness {
+                            Ok(measured) => Some(measured),
+                            Err(error) => {
+                                let _ = event_sender
+                                    .send(LibraryEvent::AnalysisFailed { track, error });
+                                None
+                            }
+                        };
+                        match library.store_loudness(track, measured.as_ref()) {
+                            Ok(()) => analyzed += 1,
+                            Err(e) => {
+                                eprintln!("Error updating library: {}", e);
+                                work.analyzing = false;
+                            }
+                        }
+                        if analyzed == ANALYSIS_BATCH {
+                            publish(&library);
+                            analyzed = 0;
+                        }
+                    }
+                }
+            }
+        })
+        .unwrap();
+
+    (command_sender, event_receiver)
+}
+
+// What the analysis thread still has to do.
+struct Work {
+    analyzing: bool,
+    drawing: bool,
+    // A waveform asked for, drawn before anything else.
+    wanted: Option<TrackId>,
+}
+
+// A track for the analysis thread to decode, by path.
+enum Job {
+    Measure(TrackId, String),
+    Draw(TrackId, String),
+}
+
+impl Work {
+    // The next track to measure or draw, `None` once there is nothing left.
+    fn next_job(&mut self, library: &Library) -> Option<Job> {
+        if let Some(track) = self.wanted.take() {
+            match library.track_path(track) {
+                Ok(Some(path)) => return Some(Job::Draw(track, path)),
+                Ok(None) => (),
+                Err(e) => eprintln!("Error reading library: {}", e),
+            }
+        }
+        if self.analyzing {
+            match library.next_unanalyzed() {
+                Ok(Some((track, path))) => return Some(Job::Measure(track, path)),
+                Ok(None) => self.analyzing = false,
+                Err(e) => {
+                    eprintln!("Error reading library: {}", e);
+                    self.analyzing = false;
+                }
+            }
+        }
+        if self.drawing {
+            let next = library.next_without_waveform().and_then(|next| match next {
+                Some(track) => Ok(library.track_path(track)?.map(|path| (track, path))),
+                None => Ok(None),
+            });
+            match next {
+                Ok(Some((track, path))) => return Some(Job::Draw(track, path)),
+                Ok(None) => self.drawing = false,
+                Err(e) => {
+                    eprintln!("Error reading library: {}", e);
+                    self.drawing = false;
+                }
+            }
+        }
+        None
+    }
+}
+
+// Measures and draws the tracks sent to it, one at a time, and sends each
+// result back to the library thread.
+fn spawn_analysis_thread(results: Sender<LibraryCommand>) -> Sender<Job> {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    thread::Builder::new()
+        .name("Analysis Thread".to_string())
+        .spawn(move || {
+            for job in receiver {
+                let result = match job {
+                    Job::Measure(track, path) => LibraryCommand::Analyzed {
+                        track,
+                        loudness: loudness::analyze(&path),
+                    },
+                    Job::Draw(track, path) => LibraryCommand::Drawn {
+                        track,
+                        waveform: waveform::analyze(&path),
+                    },
+                };
+                if results.send(result).is_err() {
+                    break;
+                }
+            }
+        })
+        .unwrap();
+    sender
+}
+
+fn watch_roots(
+    settings: &LibrarySettings,
+    sender: Sender<LibraryCommand>,
+) -> Option<RecommendedWatcher> {
+    let handler = move |result: notify::Result<Event>| match result {
+        Ok(event) if changes_files(&event.kind) => {
+            let _ = sender.send(LibraryCommand::FilesChanged(event.paths));
+        }
+        Ok(_) => (),
+        Err(e) => eprintln!("Error watching library: {}", e),
+    };
+    let mut watcher = match notify::recommended_watcher(handler) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Error creating library watcher: {}", e);
+            return None;
+        }
+    };
+    for root in &settings.roots {
+        if let Err(e) = watcher.watch(Path::new(root), RecursiveMode::Recursive) {
+            eprintln!("Error watching {}: {}", root, e);
+        }
+    }
+    Some(watcher)
+}
+
+// Reads don't change anything, but a file closed after writing has new content.
+fn changes_files(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        _ => true,
+    }
+}
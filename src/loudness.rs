@@ -0,0 +1,383 @@
+use crate::audio_track::Track;
+use crate::decoder::{DecodeError, TrackDecoder};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::fmt;
+use std::time::Duration;
+
+/// The loudness ReplayGain 2.0 brings every track to, in LUFS.
+pub const REFERENCE_LUFS: f32 = -18.0;
+/// The loudness EBU R128, and with it Opus gain tags, brings tracks to.
+pub const R128_REFERENCE_LUFS: f32 = -23.0;
+
+// Loudness is measured over 400ms blocks that overlap by 75%, built from
+// 100ms steps.
+const STEPS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+// True peak is found by upsampling 4 times with a windowed sinc filter of
+// this many taps per phase.
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum NormalizationMode {
+    Off,
+    /// Every track is played at the same loudness.
+    Track,
+    /// Albums are played at the same loudness, keeping the differences
+    /// between their tracks.
+    Album,
+}
+
+impl NormalizationMode {
+    pub const ALL: [NormalizationMode; 3] = [
+        NormalizationMode::Off,
+        NormalizationMode::Track,
+        NormalizationMode::Album,
+    ];
+}
+
+impl fmt::Display for NormalizationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NormalizationMode::Off => write!(f, "Off"),
+            NormalizationMode::Track => write!(f, "Track"),
+            NormalizationMode::Album => write!(f, "Album"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct NormalizationSettings {
+    pub mode: NormalizationMode,
+    /// Added to every gain, the reference level is quieter than most
+    /// modern masters.
+    pub preamp_db: f32,
+    /// Keeps the gain low enough that the track's peak stays below full scale.
+    pub prevent_clipping: bool,
+}
+
+impl Default for NormalizationSettings {
+    fn default() -> Self {
+        Self {
+            mode: NormalizationMode::Track,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl NormalizationSettings {
+    /// The factor to scale `track`'s samples by. Tracks without a gain of
+    /// the chosen kind use the other kind, and play unchanged without either.
+    pub fn gain(&self, track: &Track) -> f32 {
+        let track_gain = track.track_gain.map(|gain| (gain, track.track_peak));
+        let album_gain = track.album_gain.map(|gain| (gain, track.album_peak));
+        let chosen = match self.mode {
+            NormalizationMode::Off => None,
+            NormalizationMode::Track => track_gain.or(album_gain),
+            NormalizationMode::Album => album_gain.or(track_gain),
+        };
+        let Some((gain_db, peak)) = chosen else {
+            return 1.0;
+        };
+
+        let gain = 10f32.powf((gain_db + self.preamp_db) / 20.0);
+        match peak {
+            Some(peak) if self.prevent_clipping && peak > 0.0 => gain.min(1.0 / peak),
+            _ => gain,
+        }
+    }
+}
+
+/// The ReplayGain for a track or album of the given loudness.
+pub fn gain_for(loudness: f32) -> f32 {
+    REFERENCE_LUFS - loudness
+}
+
+/// The loudness of tracks played one after the other, from the loudness and
+/// duration of each. Louder tracks weigh in more, like they would when
+/// measuring the whole album at once.
+pub fn combined_loudness(tracks: &[(f32, Duration)]) -> Option<f32> {
+    let total: f64 = tracks.iter().map(|(_, d)| d.as_secs_f64()).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let power: f64 = tracks
+        .iter()
+        .map(|(loudness, d)| 10f64.powf(*loudness as f64 / 10.0) * d.as_secs_f64())
+        .sum();
+    Some((10.0 * (power / total).log10()) as f32)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, `None` for tracks that are silent.
+    pub integrated: Option<f32>,
+    /// The highest level between samples, where 1.0 is full scale.
+    pub true_peak: f32,
+}
+
+/// Decodes a whole file to measure its loudness. A file that stops decoding
+/// partway fails rather than being measured by its start.
+pub fn analyze(file_path: &str) -> Result<Loudness, DecodeError> {
+    let mut decoder = TrackDecoder::open(file_path)?;
+    let channels = decoder.channels().max(1) as usize;
+    let mut meter = LoudnessMeter::new(decoder.sample_rate(), channels);
+    let mut frame = Vec::with_capacity(channels);
+    for sample in decoder.by_ref() {
+        frame.push(sample);
+        if frame.len() == channels {
+            meter.add_frame(&frame);
+            frame.clear();
+        }
+    }
+    match decoder.error() {
+        Some(error) => Err(error.clone()),
+        None => Ok(meter.finish()),
+    }
+}
+
+/// Measures integrated loudness and true peak as described in ITU-R BS.1770.
+pub struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: [KFilter; 2],
+    // Filter state per stage and channel.
+    states: Vec<[f64; 2]>,
+    step_frames: usize,
+    step_done: usize,
+    step_power: f64,
+    // The mean power of every 100ms step so far.
+    steps: Vec<f64>,
+    // The last input samples of each channel, newest first, for upsampling.
+    history: Vec<[f32; TAPS_PER_PHASE]>,
+    interpolation: Vec<f32>,
+    true_peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: usize) -> LoudnessMeter {
+        let channels = channels.max(1);
+        let rate = sample_rate.max(1) as f64;
+        LoudnessMeter {
+            channels,
+            weights: (0..channels).map(|c| channel_weight(c, channels)).collect(),
+            filters: [KFilter::high_shelf(rate), KFilter::high_pass(rate)],
+            states: vec![[0.0; 2]; 2 * channels],
+            step_frames: (sample_rate as usize / 10).max(1),
+            step_done: 0,
+            step_power: 0.0,
+            steps: Vec::new(),
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            interpolation: interpolation_filter(),
+            true_peak: 0.0,
+        }
+    }
+
+    pub fn add_frame(&mut self, frame: &[f32]) {
+        for (channel, &sample) in frame.iter().enumerate().take(self.channels) {
+            let mut value = sample as f64;
+            for (stage, filter) in self.filters.iter().enumerate() {
+                value = filter.process(&mut self.states[stage * self.channels + channel], value);
+            }
+            self.step_power += value * value * self.weights[channel];
+            self.update_true_peak(channel, sample);
+        }
+
+        self.step_done += 1;
+        if self.step_done == self.step_frames {
+            self.steps.push(self.step_power / self.step_frames as f64);
+            self.step_done = 0;
+            self.step_power = 0.0;
+        }
+    }
+
+    fn update_true_peak(&mut self, channel: usize, sample: f32) {
+        let history = &mut self.history[channel];
+        history.copy_within(..TAPS_PER_PHASE - 1, 1);
+        history[0] = sample;
+        self.true_peak = self.true_peak.max(sample.abs());
+        for phase in 0..OVERSAMPLING {
+            let value: f32 = history
+                .iter()
+                .enumerate()
+                .map(|(tap, x)| x * self.interpolation[tap * OVERSAMPLING + phase])
+                .sum();
+            self.true_peak = self.true_peak.max(value.abs());
+        }
+    }
+
+    pub fn finish(self) -> Loudness {
+        let blocks: Vec<f64> = self
+            .steps
+            .windows(STEPS_PER_BLOCK)
+            .map(|steps| steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64)
+            .collect();
+
+        // Quiet passages would drag the measurement down, so blocks below an
+        // absolute and then a relative threshold are left out.
+        let mean_above = |threshold: f64| {
+            let gated: Vec<f64> = blocks
+                .iter()
+                .copied()
+                .filter(|&power| block_loudness(power) > threshold)
+                .collect();
+            (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+        };
+        let integrated = mean_above(ABSOLUTE_GATE_LUFS)
+            .and_then(|power| mean_above(block_loudness(power) + RELATIVE_GATE_LU))
+            .map(|power| block_loudness(power) as f32);
+
+        Loudness {
+            integrated,
+            true_peak: self.true_peak,
+        }
+    }
+}
+
+fn block_loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+// Surround channels count for more than the front ones and the LFE channel
+// of 5.1 isn't counted at all.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (5 | 6, 4 | 5) | (5, 3) => 1.41,
+        _ => 1.0,
+    }
+}
+
+// The coefficients of the windowed sinc filter that produces the samples
+// between input samples, laid out so tap `t` of phase `p` is at `t * 4 + p`.
+fn interpolation_filter() -> Vec<f32> {
+    let length = TAPS_PER_PHASE * OVERSAMPLING;
+    let centre = (length - 1) as f64 / 2.0;
+    (0..length)
+        .map(|n| {
+            let x = (n as f64 - centre) / OVERSAMPLING as f64;
+            let sinc = (PI * x).sin() / (PI * x);
+            let window = 0.5 - 0.5 * (2.0 * PI * (n as f64 + 0.5) / length as f64).cos();
+            (sinc * window) as f32
+        })
+        .collect()
+}
+
+/// One stage of BS.1770's K-weighting filter, which models how loud
+/// different frequencies sound.
+struct KFilter {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl KFilter {
+    // Lifts everything above about 1.5 kHz by 4 dB.
+    fn high_shelf(rate: f64) -> KFilter {
+        let gain_db = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * 1681.974450955533 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        KFilter {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+
+    // Cuts the lowest frequencies, which hardly add to how loud music sounds.
+    fn high_pass(rate: f64) -> KFilter {
+        let q = 0.5003270373238773;
+        let k = (PI * 38.13547087602444 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        KFilter {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+
+    fn process(&self, state: &mut [f64; 2], input: f64) -> f64 {
+        let output = self.b[0] * input + state[0];
+        state[0] = self.b[1] * input - self.a[0] * output + state[1];
+        state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(rate: u32, seconds: usize, sample: impl Fn(usize) -> f32) -> Loudness {
+        let mut meter = LoudnessMeter::new(rate, 1);
+        for i in 0..rate as usize * seconds {
+            meter.add_frame(&[sample(i)]);
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn test_sine_loudness() {
+        // A full scale 1 kHz sine measures -3.01 LUFS, half of it 6 dB less.
+        let rate = 48000;
+        let loudness = measure(rate, 5, |i| {
+            0.5 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / rate as f32).sin()
+        });
+        let integrated = loudness.integrated.unwrap();
+        assert!((integrated + 9.03).abs() < 0.1, "{}", integrated);
+    }
+
+    #[test]
+    fn test_true_peak_between_samples() {
+        // Every sample of this sine lands halfway between its peaks.
+        let loudness = measure(48000, 1, |i| {
+            (std::f32::consts::FRAC_PI_2 * i as f32 + std::f32::consts::FRAC_PI_4).sin()
+        });
+        assert!(loudness.true_peak > 0.95, "{}", loudness.true_peak);
+    }
+
+    #[test]
+    fn test_silence_has_no_loudness() {
+        assert_eq!(measure(48000, 1, |_| 0.0).integrated, None);
+    }
+
+    #[test]
+    fn test_gain_prevents_clipping() {
+        let track = Track {
+            track_gain: Some(6.0),
+            track_peak: Some(0.8),
+            album_gain: Some(-3.0),
+            ..Default::default()
+        };
+        let mut settings = NormalizationSettings::default();
+        assert!((settings.gain(&track) - 1.25).abs() < 0.001);
+
+        settings.prevent_clipping = false;
+        assert!((settings.gain(&track) - 1.995).abs() < 0.001);
+
+        settings.mode = NormalizationMode::Album;
+        assert!((settings.gain(&track) - 0.708).abs() < 0.001);
+
+        settings.mode = NormalizationMode::Off;
+        assert_eq!(settings.gain(&track), 1.0);
+    }
+
+    #[test]
+    fn test_combined_loudness() {
+        let second = Duration::from_secs(1);
+        let same = combined_loudness(&[(-10.0, second), (-10.0, second)]).unwrap();
+        assert!((same + 10.0).abs() < 0.001, "{}", same);
+        let combined = combined_loudness(&[(-10.0, second), (-20.0, second)]).unwrap();
+        assert!(combined > -13.0 && combined < -12.0, "{}", combined);
+    }
+}
@@ -0,0 +1,135 @@
+use audiopus::coder::GenericCtl;
+use audiopus::packet::Packet;
+use audiopus::{Channels, MutSignals, SampleRate};
+use std::sync::Mutex;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Result};
+use symphonia::core::formats::Packet as SymphoniaPacket;
+use symphonia::core::support_codec;
+
+// Opus always decodes at 48 kHz, whatever the rate of the original audio.
+const SAMPLE_RATE: u32 = 48_000;
+// The longest a single packet can be, 120 ms.
+const MAX_FRAMES: usize = 5760;
+
+/// Decodes Opus with libopus, for the Ogg and Matroska demuxers of symphonia,
+/// which has no Opus decoder of its own. Only mono and stereo streams are
+/// supported.
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus decoders can be moved between threads but not shared,
+    // symphonia wants both.
+    decoder: Mutex<audiopus::coder::Decoder>,
+    channels: usize,
+    // Interleaved, as libopus decodes it.
+    samples: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _: &DecoderOptions) -> Result<Self> {
+        let spec_channels = match params.channels {
+            Some(channels) => channels,
+            None => return unsupported_error("opus: channel layout is unknown"),
+        };
+        let channels = match spec_channels.count() {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            _ => return unsupported_error("opus: more than two channels"),
+        };
+        let decoder = match audiopus::coder::Decoder::new(SampleRate::Hz48000, channels) {
+            Ok(decoder) => decoder,
+            Err(_) => return decode_error("opus: could not create decoder"),
+        };
+        // The identification header carries a gain to apply to the output,
+        // in 1/256 dB
+        if let Some(gain) = params.extra_data.as_deref().and_then(output_gain) {
+            if decoder.set_gain(gain).is_err() {
+                return decode_error("opus: invalid output gain");
+            }
+        }
+
+        let channel_count = spec_channels.count();
+        let spec = SignalSpec::new(SAMPLE_RATE, spec_channels);
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channels: channel_count,
+            samples: vec![0.0; MAX_FRAMES * channel_count],
+            buf: AudioBuffer::new(MAX_FRAMES as u64, spec),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        let _ = self.decoder.get_mut().unwrap().reset_state();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &SymphoniaPacket) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+        let data = packet.buf();
+        let frames = {
+            let decoder = self.decoder.get_mut().unwrap();
+            let (Ok(input), Ok(output)) = (
+                Packet::try_from(data),
+                MutSignals::try_from(&mut self.samples[..]),
+            ) else {
+                return decode_error("opus: invalid packet");
+            };
+            match decoder.decode_float(Some(input), output, false) {
+                Ok(frames) => frames,
+                Err(_) => return decode_error("opus: invalid packet"),
+            }
+        };
+
+        self.buf.render_reserved(Some(frames));
+        for channel in 0..self.channels {
+            let plane = self.buf.chan_mut(channel);
+            for (frame, sample) in plane.iter_mut().enumerate() {
+                *sample = self.samples[frame * self.channels + channel];
+            }
+        }
+        self.buf
+            .trim(packet.trim_start() as usize, packet.trim_end() as usize);
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
+
+// The output gain of an OpusHead packet, a signed 16-bit number at bytes 16
+// and 17.
+fn output_gain(head: &[u8]) -> Option<i32> {
+    let bytes = head.get(16..18)?;
+    Some(i16::from_le_bytes([bytes[0], bytes[1]]) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_gain() {
+        let mut head = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00".to_vec();
+        assert_eq!(output_gain(&head), Some(0));
+        head[16..18].copy_from_slice(&(-512i16).to_le_bytes());
+        assert_eq!(output_gain(&head), Some(-512));
+        assert_eq!(output_gain(b"OpusHead"), None);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::player::{OutputFormat, PlayerCommand, PlayerHandle};
+use crate::position::PlaybackPosition;
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use rodio::cpal::traits::HostTrait;
+use rodio::{Device, DeviceTrait, OutputStream, Sink, Source};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How far the player's thread keeps ahead of the output, so a slow packet
+// or a busy moment doesn't leave the output without samples.
+const BUFFER_SECONDS: f64 = 0.1;
+// The output takes samples from the feed this many at a time.
+const BLOCK_SIZE: usize = 512;
+// The feed notes where in the track playback is every this many frames.
+const MARK_INTERVAL: u64 = 256;
+
+/// Something the audio thread can play through: the sound card, or nowhere
+/// at all for tests.
+pub trait Backend: Send + 'static {
+    /// The names of the devices that are currently plugged in.
+    fn device_names(&self) -> Result<Vec<String>, String>;
+    /// The device `preferred` ends up on: itself if it is plugged in,
+    /// otherwise the default device. `None` when there is no device at all.
+    fn resolve_device(&self, preferred: Option<&str>) -> Option<String>;
+    /// Starts playing `player` through the named device, converted to the
+    /// device's format.
+    fn open(&self, device_name: &str, player: &PlayerHandle) -> Result<Box<dyn Output>, String>;
+}
+
+/// An open device playing the player. Dropping it closes the device along
+/// with its end of the player's feed.
+pub trait Output {
+    fn device_name(&self) -> &str;
+    fn play(&self);
+    fn pause(&self);
+    fn set_volume(&self, volume: f32);
+}
+
+/// Plays through the sound card with cpal, by way of rodio.
+pub struct CpalBackend;
+
+impl CpalBackend {
+    // The device called `name`, or the system default if there is no such
+    // device or no name is given.
+    fn find_device(name: Option<&str>) -> Option<Device> {
+        let host = rodio::cpal::default_host();
+        let named = name.and_then(|name| {
+            host.output_devices()
+                .ok()?
+                .find(|device| device.name().is_ok_and(|n| n == name))
+        });
+        named.or_else(|| host.default_output_device())
+    }
+}
+
+impl Backend for CpalBackend {
+    fn device_names(&self) -> Result<Vec<String>, String> {
+        let devices = rodio::cpal::default_host()
+            .output_devices()
+            .map_err(|e| e.to_string())?;
+        Ok(devices.filter_map(|device| device.name().ok()).collect())
+    }
+
+    fn resolve_device(&self, preferred: Option<&str>) -> Option<String> {
+        CpalBackend::find_device(preferred)?.name().ok()
+    }
+
+    fn open(&self, device_name: &str, player: &PlayerHandle) -> Result<Box<dyn Output>, String> {
+        let device = CpalBackend::find_device(Some(device_name))
+            .filter(|device| device.name().is_ok_and(|n| n == device_name))
+            .ok_or_else(|| format!("{} is not plugged in", device_name))?;
+        let config = device.default_output_config().map_err(|e| e.to_string())?;
+        let format = OutputFormat {
+            channels: config.channels(),
+            sample_rate: config.sample_rate().0,
+        };
+        let (stream, handle) =
+            OutputStream::try_from_device_config(&device, config).map_err(|e| e.to_string())?;
+        let sink = Sink::try_new(&handle).map_err(|e| e.to_string())?;
+
+        sink.append(connect(player, format));
+        Ok(Box::new(CpalOutput {
+            _stream: stream,
+            sink,
+            device_name: device_name.to_string(),
+        }))
+    }
+}
+
+struct CpalOutput {
+    // Playback stops when the stream is dropped.
+    _stream: OutputStream,
+    sink: Sink,
+    device_name: String,
+}
+
+impl Output for CpalOutput {
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn play(&self) {
+        self.sink.play();
+    }
+
+    fn pause(&self) {
+        self.sink.pause();
+    }
+
+    fn set_volume(&self, volume: f32) {
+        self.sink.set_volume(volume);
+    }
+}
+
+/// Plays to nowhere. A thread of its own takes the player's samples at
+/// `speed` times real time, so playback runs on machines without sound
+/// hardware, and faster than real time in tests. The devices can be changed
+/// while it runs to act out plugging devices in and out.
+#[derive(Clone)]
+pub struct NullBackend {
+    pub format: OutputFormat,
+    pub speed: f64,
+    pub devices: Arc<Mutex<Vec<String>>>,
+}
+
+impl NullBackend {
+    pub fn new(format: OutputFormat, speed: f64) -> NullBackend {
+        NullBackend {
+            format,
+            speed,
+            devices: Arc::new(Mutex::new(vec!["Null".to_string()])),
+        }
+    }
+}
+
+impl Backend for NullBackend {
+    fn device_names(&self) -> Result<Vec<String>, String> {
+        Ok(self.devices.lock().unwrap().clone())
+    }
+
+    fn resolve_device(&self, preferred: Option<&str>) -> Option<String> {
+        let devices = self.devices.lock().unwrap();
+        let preferred = preferred.filter(|name| devices.iter().any(|device| device == name));
+        preferred
+            .map(str::to_string)
+            .or_else(|| devices.first().cloned())
+    }
+
+    fn open(&self, device_name: &str, player: &PlayerHandle) -> Result<Box<dyn Output>, String> {
+        let mut source = connect(player, self.format);
+        let paused = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        // 10ms of audio at a time.
+        let block = self.format.sample_rate as usize / 100 * self.format.channels as usize;
+        let interval = Duration::from_secs_f64(0.01 / self.speed.max(0.001));
+        let (thread_paused, thread_stopped) = (paused.clone(), stopped.clone());
+        let thread = thread::Builder::new()
+            .name("Null Output Thread".to_string())
+            .spawn(move || {
+                while !thread_stopped.load(Ordering::Relaxed) {
+                    if !thread_paused.load(Ordering::Relaxed) {
+                        source.by_ref().take(block).for_each(drop);
+                    }
+                    thread::sleep(interval);
+                }
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(Box::new(NullOutput {
+            device_name: device_name.to_string(),
+            paused,
+            stopped,
+            thread: Some(thread),
+        }))
+    }
+}
+
+struct NullOutput {
+    device_name: String,
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Output for NullOutput {
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn play(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    fn set_volume(&self, _volume: f32) {}
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Connects an output to the player, which plays into it in `format` from
+/// now on. Whatever was left in the previous output's feed goes with it.
+pub fn connect(player: &PlayerHandle, format: OutputFormat) -> PlayerSource {
+    let (feed, source) = feed(format, player.position.clone());
+    player.position.set_sample_rate(format.sample_rate);
+    let _ = player.commands.send(PlayerCommand::SetOutput(format, feed));
+    source
+}
+
+// A feed holding `BUFFER_SECONDS` of audio in `format`.
+fn feed(format: OutputFormat, position: PlaybackPosition) -> (Feed, PlayerSource) {
+    let channels = format.channels.max(1) as usize;
+    let frames = (format.sample_rate as f64 * BUFFER_SECONDS) as usize;
+    let (samples, sample_consumer) = HeapRb::new(frames.max(BLOCK_SIZE) * channels).split();
+    let (marks, mark_consumer) = HeapRb::new(frames / MARK_INTERVAL as usize + 2).split();
+    let flush = Arc::new(AtomicU64::new(0));
+    let feed = Feed {
+        samples,
+        marks,
+        flush: flush.clone(),
+        pushed: 0,
+        frames: 0,
+        unmarked: false,
+    };
+    let source = PlayerSource {
+        samples: sample_consumer,
+        marks: mark_consumer,
+        flush,
+        position,
+        format,
+        buffer: vec![0.0; BLOCK_SIZE / channels * channels],
+        len: 0,
+        offset: 0,
+        popped: 0,
+    };
+    (feed, source)
+}
+
+// Where in the track the frame starting at `sample` is, in frames at the
+// output's rate.
+#[derive(Clone, Copy)]
+struct Mark {
+    sample: u64,
+    frames: u64,
+}
+
+/// The player's end of the buffer between it and an output. The player's
+/// thread keeps it filled ahead and the output only ever takes from it, so
+/// neither waits on the other.
+pub struct Feed {
+    samples: HeapProducer<f32>,
+    marks: HeapProducer<Mark>,
+    // Samples before this one are skipped by the output.
+    flush: Arc<AtomicU64>,
+    pushed: u64,
+    frames: u64,
+    unmarked: bool,
+}
+
+impl Feed {
+    pub fn has_room(&self, channels: usize) -> bool {
+        self.samples.free_len() >= channels
+    }
+
+    /// Adds a frame, `frames` into the track. Check for room first, a frame
+    /// that doesn't fit is cut short.
+    pub fn push_frame(&mut self, frame: &[f32], frames: u64) {
+        if self.frames % MARK_INTERVAL == 0 || self.unmarked {
+            // The output only falls back on an older mark if this one is lost
+            let sample = self.pushed;
+            let _ = self.marks.push(Mark { sample, frames });
+            self.unmarked = false;
+        }
+        self.pushed += self.samples.push_slice(frame) as u64;
+        self.frames += 1;
+    }
+
+    /// Has the output skip what is still waiting in the feed, for when
+    /// playback jumps somewhere else.
+    pub fn flush(&mut self) {
+        self.flush.store(self.pushed, Ordering::Release);
+        self.unmarked = true;
+    }
+}
+
+/// The output's end of the feed, handed to rodio. Taking samples never
+/// blocks or allocates. When the player falls behind, silence is played
+/// until it catches up.
+pub struct PlayerSource {
+    samples: HeapConsumer<f32>,
+    marks: HeapConsumer<Mark>,
+    flush: Arc<AtomicU64>,
+    position: PlaybackPosition,
+    format: OutputFormat,
+    buffer: Vec<f32>,
+    len: usize,
+    offset: usize,
+    // Samples taken from the feed so far.
+    popped: u64,
+}
+
+impl PlayerSource {
+    // Takes the next block of whole frames from the feed, or a frame of
+    // silence if it is empty, and moves the position on to match.
+    fn refill(&mut self) {
+        let flush = self.flush.load(Ordering::Acquire);
+        if self.popped < flush {
+            self.popped += self.samples.skip((flush - self.popped) as usize) as u64;
+        }
+
+        let channels = self.format.channels.max(1) as usize;
+        let available = self.samples.len() / channels * channels;
+        let wanted = available.min(self.buffer.len());
+        self.len = self.samples.pop_slice(&mut self.buffer[..wanted]);
+        self.offset = 0;
+        if self.len == 0 {
+            self.buffer[..channels].fill(0.0);
+            self.len = channels;
+            return;
+        }
+        self.popped += self.len as u64;
+
+        loop {
+            let mark = match self.marks.iter().next() {
+                Some(&mark) if mark.sample < self.popped => mark,
+                _ => break,
+            };
+            if mark.sample >= flush {
+                self.position.set_frames(mark.frames);
+            }
+            self.marks.skip(1);
+        }
+    }
+}
+
+impl Iterator for PlayerSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.offset == self.len {
+            self.refill();
+        }
+        let sample = self.buffer[self.offset];
+        self.offset += 1;
+        Some(sample)
+    }
+}
+impl Source for PlayerSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.format.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_skips_flushed_samples() {
+        let format = OutputFormat {
+            channels: 2,
+            sample_rate: 1000,
+        };
+        let position = PlaybackPosition::new(format.sample_rate);
+        let (mut feed, mut source) = feed(format, position.clone());
+        feed.push_frame(&[1.0, 1.0], 0);
+        feed.flush();
+        feed.push_frame(&[0.5, -0.5], 500);
+
+        // Silence once the feed runs dry
+        let samples: Vec<f32> = source.by_ref().take(4).collect();
+        assert_eq!(samples, [0.5, -0.5, 0.0, 0.0]);
+        assert_eq!(position.get(), Duration::from_millis(500));
+    }
+}
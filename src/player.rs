@@ -0,0 +1,1179 @@
+use crate::audio_track::{self, Track};
+use crate::crossfade::CrossfadeSettings;
+use crate::decoder::{DecodeError, TrackDecoder};
+use crate::equalizer::{EqSettings, Equalizer};
+use crate::loudness::NormalizationSettings;
+use crate::output::Feed;
+use crate::position::PlaybackPosition;
+use crate::speed::{Speed, SpeedSettings, TimeStretch};
+use crate::visualizer::SampleTap;
+use rodio::Source;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+// Commands are picked up every this many frames, about 5ms at 48 kHz.
+const COMMAND_INTERVAL: usize = 256;
+// How long the player's thread waits while the output's feed is full.
+const FEED_WAIT: Duration = Duration::from_millis(10);
+
+/// The channel count and sample rate every track is converted to before it
+/// reaches rodio, so tracks can follow each other within one source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+/// A section of the current track played over and over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopRegion {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// A track and the decoder opened for it.
+pub struct LoadedTrack {
+    pub track: Track,
+    pub decoder: TrackDecoder,
+    /// Where in the track the decoder is, after seeking it.
+    pub position: Duration,
+}
+
+/// A loop region decoded into memory, so going round it again never waits
+/// on the file.
+pub struct LoadedLoop {
+    pub track: Track,
+    pub region: LoopRegion,
+    samples: Vec<f32>,
+    // At the end of the region, to carry on from once the loop is cleared.
+    decoder: TrackDecoder,
+}
+
+impl LoadedLoop {
+    /// Reads `region` of `track` from `decoder`, which is at its start.
+    pub fn read(
+        track: Track,
+        mut decoder: TrackDecoder,
+        region: LoopRegion,
+    ) -> Result<LoadedLoop, DecodeError> {
+        let length = region.end.saturating_sub(region.start).as_secs_f64();
+        let frames = (length * decoder.sample_rate() as f64).round() as usize;
+        let channels = decoder.channels().max(1) as usize;
+        let samples = decoder.by_ref().take(frames * channels).collect();
+        if let Some(error) = decoder.error() {
+            return Err(error.clone());
+        }
+        Ok(LoadedLoop {
+            track,
+            region,
+            samples,
+            decoder,
+        })
+    }
+}
+
+pub enum PlayerCommand {
+    /// Play into `Feed` in the given format from now on.
+    SetOutput(OutputFormat, Feed),
+    /// Replace the current track right away.
+    Play(LoadedTrack),
+    /// The track to continue with once the current one ends.
+    SetNext(Option<LoadedTrack>),
+    /// Carry on from where the decoder is, if it is for the current track.
+    /// The decoder is opened and seeked by the sender, so the player never
+    /// waits on the file.
+    Seek(LoadedTrack),
+    /// End the current track and move on to the next one.
+    Skip,
+    SetCrossfade(CrossfadeSettings),
+    SetEqualizer(EqSettings),
+    SetNormalization(NormalizationSettings),
+    SetSpeed(SpeedSettings),
+    /// Loops a section of the current track, if the loop was read from it,
+    /// or stops looping. The loop ends with the track.
+    SetLoop(Option<LoadedLoop>),
+}
+
+pub enum PlayerEvent {
+    Started {
+        track: Box<Track>,
+        duration: Option<Duration>,
+        /// Whether this is the track set with `SetNext`.
+        was_next: bool,
+    },
+    /// The current track played to its end or was skipped.
+    Ended,
+    /// There is nothing left to play.
+    Idle,
+    /// A track stopped decoding before its end and was cut short.
+    DecodeFailed {
+        track: Box<Track>,
+        error: DecodeError,
+    },
+    /// Packets of a track that couldn't be decoded were skipped. Sent once,
+    /// with all of them, when the track ends or is left.
+    PacketsDropped { track: Box<Track>, count: usize },
+}
+
+/// The audio thread's side of a `Player` running on its own thread.
+pub struct PlayerHandle {
+    pub commands: Sender<PlayerCommand>,
+    pub events: Receiver<PlayerEvent>,
+    pub position: PlaybackPosition,
+}
+
+/// A never-ending stream of samples that plays one track after another. The
+/// next track is decoded ahead and its first frame follows the last frame
+/// of the current one, so there is no gap between them, or the two are
+/// crossfaded. Silence is played while there is no track.
+///
+/// Decoding and everything after it happens on the player's own thread,
+/// which keeps the output's feed filled ahead. The output only takes the
+/// finished samples, so a slow packet never holds it up.
+pub struct Player {
+    format: OutputFormat,
+    commands: Receiver<PlayerCommand>,
+    events: Sender<PlayerEvent>,
+    feed: Option<Feed>,
+    // Set when playback jumps, so the output drops what it hasn't played yet.
+    cut: bool,
+    // How far into the current track the last frame is, at the output rate.
+    track_frames: u64,
+    crossfade: CrossfadeSettings,
+    eq_settings: EqSettings,
+    equalizer: Equalizer,
+    normalization: NormalizationSettings,
+    speed: SpeedSettings,
+    tap: Option<SampleTap>,
+    current: Option<ConvertedTrack>,
+    next: Option<ConvertedTrack>,
+    // Tracks fading out, oldest first, while the current one fades in.
+    fades: Vec<Fade>,
+    frame: Vec<f32>,
+    fade_frame: Vec<f32>,
+    frame_offset: usize,
+    until_commands: usize,
+}
+
+impl Player {
+    pub fn new(format: OutputFormat) -> (Player, PlayerHandle) {
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (event_sender, event_receiver) = mpsc::channel();
+        let position = PlaybackPosition::new(format.sample_rate);
+        let frame = vec![0.0; format.channels.max(1) as usize];
+        let player = Player {
+            format,
+            commands: command_receiver,
+            events: event_sender,
+            feed: None,
+            cut: false,
+            track_frames: 0,
+            crossfade: CrossfadeSettings::default(),
+            eq_settings: EqSettings::default(),
+            equalizer: Equalizer::new(format.sample_rate, format.channels),
+            normalization: NormalizationSettings::default(),
+            speed: SpeedSettings::default(),
+            tap: None,
+            current: None,
+            next: None,
+            fades: Vec::new(),
+            frame_offset: frame.len(),
+            fade_frame: frame.clone(),
+            frame,
+            until_commands: 0,
+        };
+        let handle = PlayerHandle {
+            commands: command_sender,
+            events: event_receiver,
+            position,
+        };
+        (player, handle)
+    }
+
+    /// Has every frame played copied into `tap` as well.
+    pub fn set_tap(&mut self, tap: SampleTap) {
+        tap.set_sample_rate(self.format.sample_rate);
+        self.tap = Some(tap);
+    }
+
+    /// Plays into the output connected with `SetOutput`, keeping its feed
+    /// filled ahead, until the handle is dropped. The player doesn't move
+    /// on while there is no output or the feed is full.
+    pub fn run(mut self) {
+        loop {
+            if self
+                .feed
+                .as_ref()
+                .is_some_and(|feed| feed.has_room(self.frame.len()))
+            {
+                self.fill_frame();
+                if let Some(feed) = &mut self.feed {
+                    if std::mem::take(&mut self.cut) {
+                        feed.flush();
+                    }
+                    feed.push_frame(&self.frame, self.track_frames);
+                }
+                continue;
+            }
+            match self.commands.recv_timeout(FEED_WAIT) {
+                Ok(command) => self.handle_command(command),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    // Converts to `format` from here on, for when playback moves to another
+    // device. The tracks carry on from where their decoders are, nothing is
+    // seeked or opened again.
+    fn set_format(&mut self, format: OutputFormat) {
+        if format == self.format {
+            return;
+        }
+        self.format = format;
+        let channels = format.channels.max(1) as usize;
+        self.frame = vec![0.0; channels];
+        self.fade_frame = vec![0.0; channels];
+        self.frame_offset = channels;
+        self.fades.clear();
+        self.equalizer = Equalizer::new(format.sample_rate, format.channels);
+        self.update_equalizer();
+        if let Some(tap) = &self.tap {
+            tap.set_sample_rate(format.sample_rate);
+        }
+        if let Some(track) = &mut self.current {
+            track.set_format(format);
+        }
+        if let Some(track) = &mut self.next {
+            track.set_format(format);
+        }
+    }
+
+    fn handle_command(&mut self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::SetOutput(format, feed) => {
+                self.set_format(format);
+                self.feed = Some(feed);
+            }
+            PlayerCommand::Play(loaded) => {
+                let track = self.convert(loaded);
+                self.change_track(track, false);
+                self.cut |= self.fades.is_empty();
+            }
+            PlayerCommand::SetNext(loaded) => {
+                self.next = loaded.map(|loaded| self.convert(loaded));
+            }
+            PlayerCommand::Seek(loaded) => {
+                let current = self.current.as_mut();
+                if let Some(track) = current.filter(|track| track.track.is_same(&loaded.track)) {
+                    self.fades.clear();
+                    track.set_decoder(loaded.decoder, loaded.position);
+                    self.track_frames = track.played as u64;
+                    self.cut = true;
+                }
+            }
+            PlayerCommand::Skip => {
+                if self.current.is_some() {
+                    self.end_track();
+                    self.cut |= self.fades.is_empty();
+                }
+            }
+            PlayerCommand::SetCrossfade(settings) => self.crossfade = settings,
+            PlayerCommand::SetEqualizer(settings) => {
+                self.eq_settings = settings;
+                self.update_equalizer();
+            }
+            PlayerCommand::SetNormalization(settings) => {
+                self.normalization = settings;
+                let fading = self.fades.iter_mut().map(|fade| &mut fade.outgoing);
+                let tracks = self.current.iter_mut().chain(self.next.iter_mut());
+                for track in tracks.chain(fading) {
+                    track.gain = self.normalization.gain(&track.track);
+                }
+            }
+            PlayerCommand::SetSpeed(settings) => {
+                self.speed = settings;
+                let fading = self.fades.iter_mut().map(|fade| &mut fade.outgoing);
+                let tracks = self.current.iter_mut().chain(self.next.iter_mut());
+                for track in tracks.chain(fading) {
+                    track.set_speed(self.speed.speed_for(&track.track));
+                }
+            }
+            PlayerCommand::SetLoop(looped) => {
+                if let Some(track) = &mut self.current {
+                    match looped {
+                        Some(looped) if looped.track.is_same(&track.track) => {
+                            track.set_loop(looped)
+                        }
+                        Some(_) => (),
+                        None => track.clear_loop(),
+                    }
+                }
+            }
+        }
+    }
+
+    fn convert(&self, loaded: LoadedTrack) -> ConvertedTrack {
+        let gain = self.normalization.gain(&loaded.track);
+        let speed = self.speed.speed_for(&loaded.track);
+        ConvertedTrack::new(loaded, self.format, gain, speed)
+    }
+
+    // Replaces the current track with `track`, fading from one to the other
+    // if the crossfade settings call for it. A track that was still fading
+    // in fades out from the level it got to, and tracks already fading out
+    // carry on with their own fades.
+    fn change_track(&mut self, track: ConvertedTrack, was_next: bool) {
+        let mut outgoing = self.current.take();
+        if let Some(outgoing) = &mut outgoing {
+            self.report_dropped_packets(outgoing);
+        }
+        match outgoing {
+            Some(outgoing) if self.crossfades(&outgoing, &track) => {
+                let length = self.fade_frames().min(outgoing.frames_left());
+                self.fades.push(Fade {
+                    from: self.incoming_gain(),
+                    outgoing,
+                    done: 0,
+                    length,
+                });
+            }
+            _ => self.fades.clear(),
+        }
+        self.start(track, was_next);
+    }
+
+    // The gain of the current track, below 1 while it fades in.
+    fn incoming_gain(&self) -> f32 {
+        match self.fades.last() {
+            Some(fade) => self.crossfade.curve.gains(fade.progress()).1,
+            None => 1.0,
+        }
+    }
+
+    fn crossfades(&self, outgoing: &ConvertedTrack, incoming: &ConvertedTrack) -> bool {
+        let settings = &self.crossfade;
+        if !settings.enabled || outgoing.ended || self.fade_frames() == 0 {
+            return false;
+        }
+        !(settings.skip_same_album && audio_track::same_album(&outgoing.track, &incoming.track))
+    }
+
+    fn fade_frames(&self) -> u64 {
+        (self.crossfade.duration.as_secs_f64() * self.format.sample_rate as f64) as u64
+    }
+
+    fn start(&mut self, track: ConvertedTrack, was_next: bool) {
+        self.track_frames = track.played as u64;
+        self.publish(PlayerEvent::Started {
+            track: Box::new(track.track.clone()),
+            duration: track.decoder.total_duration(),
+            was_next,
+        });
+        self.current = Some(track);
+        self.update_equalizer();
+    }
+
+    // Picks the equalizer preset of the current track.
+    fn update_equalizer(&mut self) {
+        let preset = match &self.current {
+            Some(current) if self.eq_settings.enabled => {
+                self.eq_settings.preset_for(&current.track)
+            }
+            _ => None,
+        };
+        self.equalizer.set_preset(preset);
+    }
+
+    fn end_track(&mut self) {
+        self.publish(PlayerEvent::Ended);
+        match self.next.take() {
+            Some(track) => self.change_track(track, true),
+            None => {
+                if let Some(mut current) = self.current.take() {
+                    self.report_dropped_packets(&mut current);
+                }
+                self.fades.clear();
+                self.publish(PlayerEvent::Idle);
+            }
+        }
+    }
+
+    // Tells of the packets `track` dropped, as it is left.
+    fn report_dropped_packets(&self, track: &mut ConvertedTrack) {
+        let count = track.take_dropped_packets();
+        if count > 0 {
+            let track = Box::new(track.track.clone());
+            self.publish(PlayerEvent::PacketsDropped { track, count });
+        }
+    }
+
+    fn publish(&self, event: PlayerEvent) {
+        // The audio thread may be gone when shutting down.
+        let _ = self.events.send(event);
+    }
+
+    fn fill_frame(&mut self) {
+        if self.until_commands == 0 {
+            self.until_commands = COMMAND_INTERVAL;
+            while let Ok(command) = self.commands.try_recv() {
+                self.handle_command(command);
+            }
+        }
+        self.until_commands -= 1;
+
+        // Start fading into the next track early enough for the fade to be
+        // over when the current one ends.
+        if let (Some(current), Some(next)) = (&self.current, &self.next) {
+            if self.fades.is_empty()
+                && current.frames_left() <= self.fade_frames()
+                && self.crossfades(current, next)
+            {
+                self.end_track();
+            }
+        }
+
+        loop {
+            match &mut self.current {
+                Some(track) => {
+                    if track.next_frame(&mut self.frame) {
+                        self.track_frames = track.played as u64;
+                        break;
+                    }
+                    if let Some(error) = track.decoder.error() {
+                        let event = PlayerEvent::DecodeFailed {
+                            track: Box::new(track.track.clone()),
+                            error: error.clone(),
+                        };
+                        self.publish(event);
+                    }
+                    self.end_track();
+                }
+                None => {
+                    self.frame.fill(0.0);
+                    break;
+                }
+            }
+        }
+
+        if !self.fades.is_empty() {
+            let incoming_gain = self.incoming_gain();
+            for sample in &mut self.frame {
+                *sample *= incoming_gain;
+            }
+            for fade in &mut self.fades {
+                if !fade.outgoing.next_frame(&mut self.fade_frame) {
+                    self.fade_frame.fill(0.0);
+                }
+                let gain = fade.from * self.crossfade.curve.gains(fade.progress()).0;
+                for (sample, outgoing) in self.frame.iter_mut().zip(&self.fade_frame) {
+                    *sample += outgoing * gain;
+                }
+                fade.done += 1;
+            }
+            self.fades.retain(|fade| fade.done < fade.length);
+        }
+
+        self.equalizer.process(&mut self.frame);
+        if let Some(tap) = &mut self.tap {
+            tap.push_frame(&self.frame);
+        }
+    }
+}
+
+impl Iterator for Player {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_offset == self.frame.len() {
+            self.fill_frame();
+            self.frame_offset = 0;
+        }
+        let sample = self.frame[self.frame_offset];
+        self.frame_offset += 1;
+        Some(sample)
+    }
+}
+
+// A track being faded out, from the gain it was at when its fade began.
+struct Fade {
+    outgoing: ConvertedTrack,
+    from: f32,
+    done: u64,
+    length: u64,
+}
+
+impl Fade {
+    fn progress(&self) -> f32 {
+        self.done as f32 / self.length.max(1) as f32
+    }
+}
+
+/// A decoder converted to the output format. Channels are mapped onto the
+/// output's and the sample rate is converted by linear interpolation, which
+/// leaves a track that already matches the output untouched. The track's
+/// normalization gain is applied on the way. The pitch is shifted by
+/// converting the rate by a bit more or less, and the time stretcher makes
+/// up the difference to the tempo.
+struct ConvertedTrack {
+    track: Track,
+    decoder: TrackDecoder,
+    format: OutputFormat,
+    gain: f32,
+    speed: Speed,
+    pitch: f64,
+    stretch: TimeStretch,
+    stretch_frame: Vec<f32>,
+    region: Option<LoopRegion>,
+    held: Option<HeldLoop>,
+    input: Vec<f32>,
+    // The input frames on either side of the output frame, mapped to the
+    // output channels, and how far between them it lies.
+    previous: Vec<f32>,
+    current: Vec<f32>,
+    phase: f64,
+    input_done: bool,
+    ended: bool,
+    // How far into the track the converted frames reach, in frames at the
+    // output rate. Each converted frame covers `pitch` of them.
+    played: f64,
+    // Packets dropped by decoders since replaced.
+    dropped_packets: usize,
+}
+
+impl ConvertedTrack {
+    fn new(loaded: LoadedTrack, format: OutputFormat, gain: f32, speed: Speed) -> ConvertedTrack {
+        let channels = format.channels.max(1) as usize;
+        let mut track = ConvertedTrack {
+            track: loaded.track,
+            decoder: loaded.decoder,
+            format,
+            gain,
+            speed,
+            pitch: 1.0,
+            stretch: TimeStretch::new(format.sample_rate, format.channels),
+            stretch_frame: vec![0.0; channels],
+            region: None,
+            held: None,
+            input: Vec::new(),
+            previous: vec![0.0; channels],
+            current: vec![0.0; channels],
+            phase: 0.0,
+            input_done: false,
+            ended: false,
+            played: loaded.position.as_secs_f64() * format.sample_rate as f64,
+            dropped_packets: 0,
+        };
+        track.set_speed(speed);
+        track.restart();
+        track
+    }
+
+    fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.pitch = speed.pitch_ratio();
+        self.stretch.set_tempo(speed.tempo as f64 / self.pitch);
+    }
+
+    // How many packets were dropped for failing to decode, since this was
+    // last called.
+    fn take_dropped_packets(&mut self) -> usize {
+        std::mem::take(&mut self.dropped_packets) + self.decoder.take_dropped_packets()
+    }
+
+    // Carries on from `decoder`, which is at `position`.
+    fn set_decoder(&mut self, decoder: TrackDecoder, position: Duration) {
+        self.dropped_packets += self.decoder.take_dropped_packets();
+        self.decoder = decoder;
+        if let Some(held) = &mut self.held {
+            held.playing = false;
+        }
+        self.restart();
+        self.played = position.as_secs_f64() * self.format.sample_rate as f64;
+    }
+
+    // Converts to `format` from the decoder's position on, losing no more
+    // than the frames the converter and time stretcher were holding.
+    fn set_format(&mut self, format: OutputFormat) {
+        let channels = format.channels.max(1) as usize;
+        self.played *= format.sample_rate as f64 / self.format.sample_rate as f64;
+        self.format = format;
+        self.previous = vec![0.0; channels];
+        self.current = vec![0.0; channels];
+        self.stretch = TimeStretch::new(format.sample_rate, format.channels);
+        self.set_speed(self.speed);
+        self.stretch_frame = vec![0.0; channels];
+        self.restart();
+    }
+
+    // Plays from the decoder until the end of the region, and from the
+    // samples in memory on every time round after that.
+    fn set_loop(&mut self, looped: LoadedLoop) {
+        self.region = Some(looped.region);
+        self.held = Some(HeldLoop {
+            samples: looped.samples,
+            offset: 0,
+            playing: false,
+            decoder: Some(looped.decoder),
+        });
+    }
+
+    // Stops looping. A loop being played from memory is played to its end
+    // first, and the track carries on from there.
+    fn clear_loop(&mut self) {
+        self.region = None;
+        if self.held.as_ref().is_some_and(|held| !held.playing) {
+            self.held = None;
+        }
+    }
+
+    // Output frames until the end of the track at its speed. Looping tracks
+    // and tracks of unknown length never end as far as fading is concerned.
+    fn frames_left(&self) -> u64 {
+        if self.region.is_some() {
+            return u64::MAX;
+        }
+        match self.decoder.total_duration() {
+            Some(duration) => {
+                let total = duration.as_secs_f64() * self.format.sample_rate as f64;
+                ((total - self.played).max(0.0) / self.speed.tempo as f64) as u64
+            }
+            None => u64::MAX,
+        }
+    }
+
+    // Starts over after opening or seeking.
+    fn restart(&mut self) {
+        self.stretch.reset();
+        self.reload();
+    }
+
+    // Loads the first two input frames at the decoder's position.
+    fn reload(&mut self) {
+        self.phase = 0.0;
+        self.input_done = false;
+        self.ended = !self.read_input();
+        if !self.ended {
+            map_channels(&self.input, &mut self.previous);
+        }
+        if self.read_input() {
+            map_channels(&self.input, &mut self.current);
+        } else {
+            self.current.copy_from_slice(&self.previous);
+            self.input_done = true;
+        }
+    }
+
+    fn next_frame(&mut self, output: &mut [f32]) -> bool {
+        if !self.stretch.is_active() {
+            return self.convert_frame(output);
+        }
+        loop {
+            if self.stretch.pop_frame(output) {
+                return true;
+            }
+            if self.stretch.is_finished() {
+                return false;
+            }
+            let mut frame = std::mem::take(&mut self.stretch_frame);
+            if self.convert_frame(&mut frame) {
+                self.stretch.push_frame(&frame);
+            } else {
+                self.stretch.finish();
+            }
+            self.stretch_frame = frame;
+        }
+    }
+
+    fn convert_frame(&mut self, output: &mut [f32]) -> bool {
+        if self.ended {
+            return false;
+        }
+        let phase = self.phase as f32;
+        for ((out, previous), current) in output.iter_mut().zip(&self.previous).zip(&self.current) {
+            *out = (previous + (current - previous) * phase) * self.gain;
+        }
+        self.played += self.pitch;
+
+        self.phase +=
+            self.decoder.sample_rate() as f64 / self.format.sample_rate as f64 * self.pitch;
+        while self.phase >= 1.0 {
+            self.phase -= 1.0;
+            if self.input_done {
+                self.ended = true;
+                break;
+            }
+            std::mem::swap(&mut self.previous, &mut self.current);
+            if self.read_input() {
+                map_channels(&self.input, &mut self.current);
+            } else {
+                self.current.copy_from_slice(&self.previous);
+                self.input_done = true;
+            }
+        }
+        self.wrap_loop();
+        true
+    }
+
+    // Jumps back to the start of the loop once its end is reached, the
+    // frame after its last one is the first one of the loop. The time
+    // stretcher carries on across the jump, so there is no fade in either.
+    fn wrap_loop(&mut self) {
+        let (Some(region), Some(held)) = (self.region, &mut self.held) else {
+            return;
+        };
+        let rate = self.format.sample_rate as f64;
+        if !self.ended && self.played < (region.end.as_secs_f64() * rate).round() {
+            return;
+        }
+        // The decoder that read the loop is at its end, for when the loop
+        // is cleared
+        if let Some(decoder) = held.decoder.take() {
+            self.dropped_packets += self.decoder.take_dropped_packets();
+            self.decoder = decoder;
+        }
+        held.offset = 0;
+        held.playing = true;
+        self.reload();
+        self.played = region.start.as_secs_f64() * rate;
+    }
+
+    // Reads the next input frame into `input`, from memory while a loop is
+    // played from there. Returns false at the end of the track, or at the
+    // end of the loop while it is still looping.
+    fn read_input(&mut self) -> bool {
+        let channels = self.decoder.channels().max(1) as usize;
+        self.input.clear();
+        if let Some(held) = self.held.as_mut().filter(|held| held.playing) {
+            if let Some(frame) = held.samples.get(held.offset..held.offset + channels) {
+                self.input.extend_from_slice(frame);
+                held.offset += channels;
+                return true;
+            }
+            if self.region.is_some() {
+                return false;
+            }
+            self.held = None;
+        }
+        for _ in 0..channels {
+            match self.decoder.next() {
+                Some(sample) => self.input.push(sample),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+// A loop region's samples, played from `offset` once the decoder has been
+// through the region once.
+struct HeldLoop {
+    samples: Vec<f32>,
+    offset: usize,
+    playing: bool,
+    // Replaces the track's decoder the first time round.
+    decoder: Option<TrackDecoder>,
+}
+
+// Mono is spread over every output channel and everything is mixed down for
+// a mono output. Otherwise channels are matched up by position.
+fn map_channels(input: &[f32], output: &mut [f32]) {
+    if output.len() == 1 {
+        output[0] = input.iter().sum::<f32>() / input.len() as f32;
+        return;
+    }
+    for (channel, out) in output.iter_mut().enumerate() {
+        *out = input[channel % input.len()];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crossfade::FadeCurve;
+
+    fn open(path: &str) -> TrackDecoder {
+        TrackDecoder::open(path).unwrap()
+    }
+
+    fn loaded(path: &str, decoder: TrackDecoder) -> LoadedTrack {
+        let track = Track {
+            file_path: path.to_string(),
+            ..Default::default()
+        };
+        LoadedTrack {
+            track,
+            decoder,
+            position: Duration::ZERO,
+        }
+    }
+
+    fn load(path: &str) -> LoadedTrack {
+        loaded(path, open(path))
+    }
+
+    fn load_at(path: &str, position: Duration) -> LoadedTrack {
+        let mut loaded = load(path);
+        loaded.decoder.seek(position).unwrap();
+        loaded.position = position;
+        loaded
+    }
+
+    // How far into the current track the player's last frame is.
+    fn position(player: &Player) -> Duration {
+        let seconds = player.track_frames as f64 / player.format.sample_rate as f64;
+        Duration::from_secs_f64(seconds)
+    }
+
+    fn format_of(decoder: &TrackDecoder) -> OutputFormat {
+        OutputFormat {
+            channels: decoder.channels(),
+            sample_rate: decoder.sample_rate(),
+        }
+    }
+
+    fn load_loop(path: &str, region: LoopRegion) -> LoadedLoop {
+        let loaded = load_at(path, region.start);
+        LoadedLoop::read(loaded.track, loaded.decoder, region).unwrap()
+    }
+
+    #[test]
+    fn test_map_channels() {
+        let mut stereo = [0.0; 2];
+        map_channels(&[0.5], &mut stereo);
+        assert_eq!(stereo, [0.5, 0.5]);
+
+        let mut mono = [0.0; 1];
+        map_channels(&[0.2, 0.4], &mut mono);
+        assert!((mono[0] - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_plays_next_track_without_gap() {
+        let first = "assets/tracks/filler1.wav";
+        let second = "assets/tracks/filler2.wav";
+        let rate = open(first).sample_rate() as usize;
+
+        // The last half second of the first track followed by the start of
+        // the second, decoded on their own.
+        let mut expected: Vec<f32> = open(first).skip(rate * 59 + rate / 2).collect();
+        expected.extend(open(second).take(rate / 2));
+
+        let (mut player, handle) = Player::new(format_of(&open(first)));
+        handle
+            .commands
+            .send(PlayerCommand::Play(load(first)))
+            .unwrap();
+        handle
+            .commands
+            .send(PlayerCommand::SetNext(Some(load(second))))
+            .unwrap();
+        let position = Duration::from_millis(59_500);
+        handle
+            .commands
+            .send(PlayerCommand::Seek(load_at(first, position)))
+            .unwrap();
+
+        let actual: Vec<f32> = player.by_ref().take(expected.len()).collect();
+        assert_eq!(actual, expected);
+
+        let events: Vec<PlayerEvent> = handle.events.try_iter().collect();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                PlayerEvent::Started {
+                    was_next: false,
+                    ..
+                },
+                PlayerEvent::Ended,
+                PlayerEvent::Started { was_next: true, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_crossfades_on_skip() {
+        let first = "assets/tracks/filler1.wav";
+        let second = "assets/tracks/filler2.wav";
+        let crossfade = CrossfadeSettings {
+            enabled: true,
+            duration: Duration::from_millis(100),
+            curve: FadeCurve::Linear,
+            skip_same_album: true,
+        };
+        let (mut player, handle) = Player::new(format_of(&open(first)));
+        let length = (format_of(&open(first)).sample_rate / 10) as usize;
+
+        // The files have the same audio, start the second one further in
+        let open_second = || {
+            let mut decoder = open(second);
+            decoder.seek(Duration::from_secs(30)).unwrap();
+            decoder
+        };
+        let outgoing: Vec<f32> = open(first).take(length + 10).collect();
+        let incoming: Vec<f32> = open_second().take(length + 10).collect();
+        assert_ne!(outgoing, incoming);
+        let expected: Vec<f32> = (0..length + 10)
+            .map(|i| {
+                let (outgoing_gain, incoming_gain) =
+                    FadeCurve::Linear.gains(i as f32 / length as f32);
+                if i < length {
+                    incoming[i] * incoming_gain + outgoing[i] * outgoing_gain
+                } else {
+                    incoming[i]
+                }
+            })
+            .collect();
+
+        for command in [
+            PlayerCommand::SetCrossfade(crossfade),
+            PlayerCommand::Play(load(first)),
+            PlayerCommand::SetNext(Some(loaded(second, open_second()))),
+            PlayerCommand::Skip,
+        ] {
+            handle.commands.send(command).unwrap();
+        }
+        let actual: Vec<f32> = player.by_ref().take(expected.len()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_skips_during_crossfade() {
+        let first = "assets/tracks/filler1.wav";
+        let second = "assets/tracks/filler2.wav";
+        let crossfade = CrossfadeSettings {
+            enabled: true,
+            duration: Duration::from_millis(100),
+            curve: FadeCurve::Linear,
+            skip_same_album: true,
+        };
+        let format = format_of(&open(first));
+        let channels = format.channels as usize;
+        let length = (format.sample_rate / 10) as usize;
+        // Commands are picked up every `COMMAND_INTERVAL` frames
+        let skipped_at = COMMAND_INTERVAL * 8;
+
+        let open_at = |path: &str, seconds: u64| {
+            let mut decoder = open(path);
+            decoder.seek(Duration::from_secs(seconds)).unwrap();
+            decoder
+        };
+        let samples = (skipped_at + length) * channels;
+        let a: Vec<f32> = open(first).take(samples).collect();
+        let b: Vec<f32> = open_at(second, 30).take(samples).collect();
+        let c: Vec<f32> = open_at(first, 10).take(samples).collect();
+        let gains = |done: usize| FadeCurve::Linear.gains(done as f32 / length as f32);
+
+        // The second track fades out from where it had faded in to, while
+        // the first carries on fading out and the third fades in
+        let from = gains(skipped_at).1;
+        let expected: Vec<f32> = (0..samples)
+            .map(|i| {
+                let frame = i / channels;
+                if frame < skipped_at {
+                    let (out, inc) = gains(frame);
+                    return b[i] * inc + a[i] * out;
+                }
+                let j = i - skipped_at * channels;
+                let mut sample = c[j] * gains(frame - skipped_at).1;
+                if frame < length {
+                    sample += a[i] * (1.0 * gains(frame).0);
+                }
+                sample + b[i] * (from * gains(frame - skipped_at).0)
+            })
+            .collect();
+
+        let (mut player, handle) = Player::new(format);
+        for command in [
+            PlayerCommand::SetCrossfade(crossfade),
+            PlayerCommand::Play(load(first)),
+            PlayerCommand::SetNext(Some(loaded(second, open_at(second, 30)))),
+            PlayerCommand::Skip,
+        ] {
+            handle.commands.send(command).unwrap();
+        }
+        let mut actual: Vec<f32> = player.by_ref().take(skipped_at * channels).collect();
+        for command in [
+            PlayerCommand::SetNext(Some(loaded(first, open_at(first, 10)))),
+            PlayerCommand::Skip,
+        ] {
+            handle.commands.send(command).unwrap();
+        }
+        actual.extend(player.by_ref().take(samples - actual.len()));
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_converts_sample_rate() {
+        let path = "assets/tracks/CantinaBand60.wav";
+        let format = OutputFormat {
+            channels: 2,
+            sample_rate: open(path).sample_rate() * 2,
+        };
+        let (mut player, handle) = Player::new(format);
+        handle
+            .commands
+            .send(PlayerCommand::Play(load(path)))
+            .unwrap();
+
+        // One second of output is one second of the track
+        player
+            .by_ref()
+            .take(format.sample_rate as usize * 2)
+            .for_each(drop);
+        assert_eq!(position(&player), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_plays_at_speed() {
+        let path = "assets/tracks/CantinaBand60.wav";
+        let format = format_of(&open(path));
+        for speed in [
+            Speed {
+                tempo: 2.0,
+                pitch: 0.0,
+            },
+            Speed {
+                tempo: 2.0,
+                pitch: 5.0,
+            },
+        ] {
+            let (mut player, handle) = Player::new(format);
+            let settings = SpeedSettings {
+                default: speed,
+                ..Default::default()
+            };
+            handle
+                .commands
+                .send(PlayerCommand::SetSpeed(settings))
+                .unwrap();
+            handle
+                .commands
+                .send(PlayerCommand::Play(load(path)))
+                .unwrap();
+
+            // Half a second of output covers a second of the track, give or
+            // take what the time stretcher holds on to
+            player
+                .by_ref()
+                .take(format.sample_rate as usize / 2)
+                .for_each(drop);
+            let position = position(&player).as_secs_f32();
+            assert!((position - 1.0).abs() < 0.1, "{:?} {}", speed, position);
+        }
+    }
+
+    #[test]
+    fn test_loops_region_exactly() {
+        let path = "assets/tracks/CantinaBand60.wav";
+        let rate = open(path).sample_rate() as usize;
+        let region = LoopRegion {
+            start: Duration::from_secs(1),
+            end: Duration::from_millis(1100),
+        };
+        let section: Vec<f32> = open(path).skip(rate).take(rate / 10).collect();
+        let expected: Vec<f32> = section.repeat(3);
+
+        let (mut player, handle) = Player::new(format_of(&open(path)));
+        handle
+            .commands
+            .send(PlayerCommand::Play(load_at(path, region.start)))
+            .unwrap();
+        handle
+            .commands
+            .send(PlayerCommand::SetLoop(Some(load_loop(path, region))))
+            .unwrap();
+
+        let actual: Vec<f32> = player.by_ref().take(expected.len()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_plays_on_after_loop_is_cleared() {
+        let path = "assets/tracks/CantinaBand60.wav";
+        let rate = open(path).sample_rate() as usize;
+        let region = LoopRegion {
+            start: Duration::from_secs(1),
+            end: Duration::from_millis(1100),
+        };
+        // Round once, then the loop is cleared halfway through the second
+        // time and the track carries on past its end
+        let mut expected: Vec<f32> = open(path).skip(rate).take(rate / 10).collect();
+        expected.extend(open(path).skip(rate).take(rate / 5));
+        let halfway = rate / 10 + rate / 20;
+
+        let (mut player, handle) = Player::new(format_of(&open(path)));
+        handle
+            .commands
+            .send(PlayerCommand::Play(load_at(path, region.start)))
+            .unwrap();
+        handle
+            .commands
+            .send(PlayerCommand::SetLoop(Some(load_loop(path, region))))
+            .unwrap();
+        let mut actual: Vec<f32> = player.by_ref().take(halfway).collect();
+        handle.commands.send(PlayerCommand::SetLoop(None)).unwrap();
+        actual.extend(player.by_ref().take(expected.len() - halfway));
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_ignores_seek_for_other_track() {
+        let first = "assets/tracks/filler1.wav";
+        let second = "assets/tracks/filler2.wav";
+        let expected: Vec<f32> = open(first).take(1000).collect();
+
+        let (mut player, handle) = Player::new(format_of(&open(first)));
+        handle
+            .commands
+            .send(PlayerCommand::Play(load(first)))
+            .unwrap();
+        // Meant for a track that has already been replaced
+        let position = Duration::from_secs(30);
+        handle
+            .commands
+            .send(PlayerCommand::Seek(load_at(second, position)))
+            .unwrap();
+
+        let actual: Vec<f32> = player.by_ref().take(expected.len()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_goes_idle_after_skip() {
+        let path = "assets/tracks/CantinaBand60.wav";
+        let (mut player, handle) = Player::new(format_of(&open(path)));
+        handle
+            .commands
+            .send(PlayerCommand::Play(load(path)))
+            .unwrap();
+        handle.commands.send(PlayerCommand::Skip).unwrap();
+
+        assert_eq!(player.next(), Some(0.0));
+        let events: Vec<PlayerEvent> = handle.events.try_iter().collect();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                PlayerEvent::Started { .. },
+                PlayerEvent::Ended,
+                PlayerEvent::Idle
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_applies_normalization_gain() {
+        let path = "assets/tracks/CantinaBand60.wav";
+        let expected: Vec<f32> = open(path).take(1000).map(|s| s * 0.5).collect();
+
+        let (mut player, handle) = Player::new(format_of(&open(path)));
+        let mut track = load(path);
+        track.track.track_gain = Some(20.0 * 0.5f32.log10());
+        handle.commands.send(PlayerCommand::Play(track)).unwrap();
+
+        let actual: Vec<f32> = player.by_ref().take(expected.len()).collect();
+        for (actual, expected) in actual.iter().zip(&expected) {
+            assert!((actual - expected).abs() < 1e-6);
+        }
+    }
+}
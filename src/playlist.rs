@@ -1,26 +1,84 @@
 use crate::audio_track::Track;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Identifies one entry in a playlist, so the same track added twice can
+/// still be told apart.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(transparent)]
+pub struct EntryId(pub u64);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlaylistEntry {
+    pub id: EntryId,
+    pub track: Track,
+}
 
 #[derive(Serialize, Deserialize)]
+#[serde(from = "SavedPlaylist")]
 pub struct Playlist {
     pub name: String,
-    pub tracks: Vec<Track>,
+    pub entries: Vec<PlaylistEntry>,
+    next_entry_id: u64,
+}
+
+// What playlists are read back from. Playlists saved before entries had IDs
+// only have their tracks, those get numbered when loaded.
+#[derive(Deserialize)]
+struct SavedPlaylist {
+    name: String,
+    #[serde(default)]
+    entries: Vec<PlaylistEntry>,
+    #[serde(default)]
+    next_entry_id: u64,
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+impl From<SavedPlaylist> for Playlist {
+    fn from(saved: SavedPlaylist) -> Self {
+        let mut playlist = Playlist {
+            name: saved.name,
+            entries: saved.entries,
+            next_entry_id: saved.next_entry_id,
+        };
+        for track in saved.tracks {
+            playlist.add_track(track);
+        }
+        playlist
+    }
 }
 
 impl Playlist {
     pub fn new(name: String) -> Self {
         Self {
             name,
-            tracks: Vec::new(),
+            entries: Vec::new(),
+            next_entry_id: 0,
         }
     }
 
-    pub fn add_track(&mut self, track: Track) {
-        self.tracks.push(track);
+    pub fn add_track(&mut self, track: Track) -> EntryId {
+        let id = EntryId(self.next_entry_id);
+        self.next_entry_id += 1;
+        self.entries.push(PlaylistEntry { id, track });
+        id
+    }
+
+    /// Removes a single entry, leaving other copies of its track in place.
+    pub fn remove_entry(&mut self, id: EntryId) {
+        self.entries.retain(|entry| entry.id != id);
+    }
+
+    pub fn tracks(&self) -> Vec<Track> {
+        self.entries
+            .iter()
+            .map(|entry| entry.track.clone())
+            .collect()
     }
 
-    pub fn remove_track(&mut self, track: Track) {
-        self.tracks.retain(|t| t.file_path != track.file_path);
+    pub fn tracks_mut(&mut self) -> impl Iterator<Item = &mut Track> {
+        self.entries.iter_mut().map(|entry| &mut entry.track)
     }
 
     pub fn rename(&mut self, name: String) {
@@ -28,6 +86,46 @@ impl Playlist {
     }
 
     pub fn delete(&mut self) {
-        self.tracks.clear();
+        self.entries.clear();
+    }
+
+    pub fn total_duration(&self) -> Duration {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.track.duration)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str) -> Track {
+        Track {
+            title: title.to_string(),
+            file_path: format!("{}.mp3", title),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_remove_entry_keeps_duplicates() {
+        let mut playlist = Playlist::new("Test".to_string());
+        let first = playlist.add_track(track("a"));
+        playlist.add_track(track("b"));
+        playlist.add_track(track("a"));
+
+        playlist.remove_entry(first);
+        let titles: Vec<String> = playlist.tracks().into_iter().map(|t| t.title).collect();
+        assert_eq!(titles, ["b", "a"]);
+    }
+
+    #[test]
+    fn test_entry_ids_stay_unique() {
+        let mut playlist = Playlist::new("Test".to_string());
+        let first = playlist.add_track(track("a"));
+        playlist.remove_entry(first);
+        assert_ne!(playlist.add_track(track("a")), first);
     }
 }
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How far into the current track playback is, measured in frames at the
+/// output's sample rate. It is shared between the player, which moves it on
+/// as rodio pulls samples, and the audio thread, which reads it. At other
+/// speeds a frame handed to the output covers more or less of the track.
+/// Rodio stops pulling while the sink is paused, so the count is what was
+/// actually played.
+#[derive(Clone)]
+pub struct PlaybackPosition {
+    frames: Arc<AtomicU64>,
+    // Changes when playback moves to a device running at another rate.
+    sample_rate: Arc<AtomicU32>,
+}
+
+impl PlaybackPosition {
+    pub fn new(sample_rate: u32) -> PlaybackPosition {
+        PlaybackPosition {
+            frames: Arc::new(AtomicU64::new(0)),
+            sample_rate: Arc::new(AtomicU32::new(sample_rate.max(1))),
+        }
+    }
+
+    pub fn get(&self) -> Duration {
+        let frames = self.frames.load(Ordering::Relaxed);
+        Duration::from_secs_f64(frames as f64 / self.sample_rate() as f64)
+    }
+
+    pub fn set(&self, position: Duration) {
+        let frames = (position.as_secs_f64() * self.sample_rate() as f64) as u64;
+        self.frames.store(frames, Ordering::Relaxed);
+    }
+
+    /// Moves the position to `frames` into the track, at the current rate.
+    pub fn set_frames(&self, frames: u64) {
+        self.frames.store(frames, Ordering::Relaxed);
+    }
+
+    /// Counts frames at `sample_rate` from now on, keeping the position.
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        let position = self.get();
+        self.sample_rate
+            .store(sample_rate.max(1), Ordering::Relaxed);
+        self.set(position);
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_frames() {
+        let position = PlaybackPosition::new(1000);
+        assert_eq!(position.get(), Duration::ZERO);
+
+        position.set_frames(500);
+        assert_eq!(position.get(), Duration::from_millis(500));
+
+        position.set_sample_rate(2000);
+        assert_eq!(position.get(), Duration::from_millis(500));
+        position.set_frames(1001);
+        assert_eq!(position.get(), Duration::from_micros(500_500));
+    }
+}
@@ -1,9 +1,84 @@
+use crate::audio_track;
 use crate::audio_track::Track;
+use crate::audio_track::TrackId;
+use rand::seq::SliceRandom;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
 
+// How many played tracks are remembered.
+const HISTORY_LENGTH: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Plays the current track over and over.
+    One,
+    /// Tracks go back to the end of the queue once they start, so the queue
+    /// never runs out.
+    All,
+}
+
+impl RepeatMode {
+    pub const ALL: [RepeatMode; 3] = [RepeatMode::Off, RepeatMode::One, RepeatMode::All];
+}
+
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepeatMode::Off => write!(f, "Repeat off"),
+            RepeatMode::One => write!(f, "Repeat one"),
+            RepeatMode::All => write!(f, "Repeat all"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    Random,
+    /// Random, but keeps tracks by the same artist apart where it can.
+    Smart,
+}
+
+impl ShuffleMode {
+    pub const ALL: [ShuffleMode; 3] = [ShuffleMode::Off, ShuffleMode::Random, ShuffleMode::Smart];
+}
+
+impl fmt::Display for ShuffleMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShuffleMode::Off => write!(f, "Shuffle off"),
+            ShuffleMode::Random => write!(f, "Shuffle"),
+            ShuffleMode::Smart => write!(f, "Smart shuffle"),
+        }
+    }
+}
+
+/// The tracks waiting to be played. `tracks` always keeps the order they
+/// were added in; shuffling only changes the order they are played in, so
+/// turning it off again restores the original order.
 #[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Queue {
     pub tracks: Vec<Track>,
+    /// The tracks played before the current one, the most recent last.
+    pub history: Vec<Track>,
+    pub repeat: RepeatMode,
+    shuffle: ShuffleMode,
+    // While shuffled, the order to play `tracks` in as indices into it.
+    order: Vec<usize>,
+    // Bumped whenever positions in the play order change.
+    #[serde(skip)]
+    revision: u64,
+    // Tracks that failed to play. They stay in the queue but are passed
+    // over until retried.
+    #[serde(skip)]
+    failed: BTreeSet<TrackId>,
 }
 
 impl Queue {
@@ -12,16 +87,385 @@ impl Queue {
     }
 
     pub fn add_track(&mut self, track: Track) {
-        let title = track.title.clone();
+        self.revision += 1;
         self.tracks.push(track);
-        println!("The track {} has been added to the queue", title);
+        if self.is_shuffled() {
+            let position = self.shuffled_position(self.tracks.len() - 1);
+            self.order.insert(position, self.tracks.len() - 1);
+        }
+    }
+
+    /// Adds tracks at the end, in order while not shuffled.
+    pub fn add_tracks(&mut self, tracks: Vec<Track>) {
+        for track in tracks {
+            self.add_track(track);
+        }
+    }
+
+    /// Puts a track at `position` in the play order, so 0 plays next, or at
+    /// the end if `position` is past it.
+    pub fn insert(&mut self, position: usize, track: Track) {
+        let position = position.min(self.tracks.len());
+        self.revision += 1;
+        if self.is_shuffled() {
+            self.tracks.push(track);
+            self.order.insert(position, self.tracks.len() - 1);
+        } else {
+            self.tracks.insert(position, track);
+        }
+    }
+
+    /// Moves the track at position `from` in the play order to `to`, or to
+    /// the end if `to` is past it. Does nothing if there is no track at
+    /// `from`.
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        if from >= self.tracks.len() {
+            return;
+        }
+        let to = to.min(self.tracks.len() - 1);
+        self.revision += 1;
+        if self.is_shuffled() {
+            let index = self.order.remove(from);
+            self.order.insert(to, index);
+        } else {
+            let track = self.tracks.remove(from);
+            self.tracks.insert(to, track);
+        }
+    }
+
+    /// Takes the track at `position` in the play order off the queue, `None`
+    /// if there is no track there.
+    pub fn remove(&mut self, position: usize) -> Option<Track> {
+        if position >= self.tracks.len() {
+            return None;
+        }
+        let index = if self.is_shuffled() {
+            self.order[position]
+        } else {
+            position
+        };
+        Some(self.remove_index(index))
     }
 
-    pub fn remove_track(&mut self, track: Track) {
-        self.tracks.retain(|t| t.file_path != track.file_path);
+    /// Empties the queue, keeping the history and modes.
+    pub fn clear(&mut self) {
+        self.revision += 1;
+        self.tracks.clear();
+        self.order.clear();
     }
 
+    /// The track that plays next, passing over tracks that failed.
+    pub fn peek(&self) -> Option<&Track> {
+        self.next_index().map(|index| &self.tracks[index])
+    }
+
+    /// Takes the track that plays next off the queue, passing over tracks
+    /// that failed. With repeat all it goes back in at the end.
+    pub fn pop_next(&mut self) -> Option<Track> {
+        let track = self.remove_index(self.next_index()?);
+        if self.repeat == RepeatMode::All {
+            self.tracks.push(track.clone());
+            if self.is_shuffled() {
+                self.order.push(self.tracks.len() - 1);
+            }
+        }
+        Some(track)
+    }
+
+    /// Drops tracks whose file is gone from the front of the queue, they
+    /// can't be played.
+    pub fn skip_missing(&mut self) {
+        while let Some(index) = self.next_index().filter(|&i| self.tracks[i].missing) {
+            self.remove_index(index);
+        }
+    }
+
+    /// Passes over `track` from now on, until it is retried.
+    pub fn mark_failed(&mut self, track: TrackId) {
+        self.failed.insert(track);
+    }
+
+    /// Lets a track that failed play from the queue again.
+    pub fn retry(&mut self, track: TrackId) {
+        self.failed.remove(&track);
+    }
+
+    /// Whether `track` failed and is passed over.
+    pub fn has_failed(&self, track: &Track) -> bool {
+        track.id.is_some_and(|id| self.failed.contains(&id))
+    }
+
+    pub fn shuffle(&self) -> ShuffleMode {
+        self.shuffle
+    }
+
+    pub fn set_shuffle(&mut self, mode: ShuffleMode) {
+        self.revision += 1;
+        self.shuffle = mode;
+        self.order.clear();
+        if mode == ShuffleMode::Off {
+            return;
+        }
+        let mut pool: Vec<usize> = (0..self.tracks.len()).collect();
+        pool.shuffle(&mut rand::thread_rng());
+        if mode == ShuffleMode::Random {
+            self.order = pool;
+            return;
+        }
+        // Take the first track in the shuffled pool whose artist differs from
+        // the last one picked, or any track once only that artist is left.
+        while !pool.is_empty() {
+            let last = self.order.last().map(|&index| &self.tracks[index]);
+            let pick = pool
+                .iter()
+                .position(|&index| !same_artist(last, Some(&self.tracks[index])))
+                .unwrap_or(0);
+            self.order.push(pool.remove(pick));
+        }
+    }
+
+    /// Remembers a track that finished or was left for another one.
+    pub fn push_history(&mut self, track: Track) {
+        self.history.push(track);
+        if self.history.len() > HISTORY_LENGTH {
+            self.history.remove(0);
+        }
+    }
+
+    /// The tracks in the order they will be played.
     pub fn get_tracks(&self) -> Vec<Track> {
-        self.tracks.clone()
+        if self.is_shuffled() {
+            self.order.iter().map(|&i| self.tracks[i].clone()).collect()
+        } else {
+            self.tracks.clone()
+        }
+    }
+
+    pub fn total_duration(&self) -> Duration {
+        audio_track::total_duration(&self.tracks)
+    }
+
+    /// Changes whenever tracks are added, removed or reordered, so positions
+    /// taken from an earlier revision are known to be stale.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn is_shuffled(&self) -> bool {
+        // Queues saved without their order are played unshuffled.
+        self.shuffle != ShuffleMode::Off && self.order.len() == self.tracks.len()
+    }
+
+    fn next_index(&self) -> Option<usize> {
+        let playable = |&index: &usize| !self.has_failed(&self.tracks[index]);
+        if self.is_shuffled() {
+            self.order.iter().copied().find(playable)
+        } else {
+            (0..self.tracks.len()).find(playable)
+        }
+    }
+
+    fn remove_index(&mut self, index: usize) -> Track {
+        self.revision += 1;
+        let shuffled = self.is_shuffled();
+        let track = self.tracks.remove(index);
+        if shuffled {
+            self.order.retain(|&i| i != index);
+            for i in &mut self.order {
+                if *i > index {
+                    *i -= 1;
+                }
+            }
+        }
+        track
+    }
+
+    // Where a newly added track goes in the play order: anywhere for random
+    // shuffle, and away from tracks by the same artist for smart shuffle.
+    fn shuffled_position(&self, index: usize) -> usize {
+        let track = Some(&self.tracks[index]);
+        let fits = |position: usize| {
+            let before = position.checked_sub(1).map(|p| &self.tracks[self.order[p]]);
+            let after = self.order.get(position).map(|&i| &self.tracks[i]);
+            !same_artist(before, track) && !same_artist(after, track)
+        };
+        let positions: Vec<usize> = (0..=self.order.len())
+            .filter(|&position| self.shuffle != ShuffleMode::Smart || fits(position))
+            .collect();
+        match positions.choose(&mut rand::thread_rng()) {
+            Some(&position) => position,
+            None => rand::thread_rng().gen_range(0..=self.order.len()),
+        }
+    }
+}
+
+fn same_artist(a: Option<&Track>, b: Option<&Track>) -> bool {
+    match (
+        a.and_then(|t| t.artist.as_ref()),
+        b.and_then(|t| t.artist.as_ref()),
+    ) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, artist: &str) -> Track {
+        Track {
+            title: title.to_string(),
+            file_path: format!("{}.mp3", title),
+            artist: Some(artist.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn titles(tracks: &[Track]) -> Vec<String> {
+        tracks.iter().map(|t| t.title.clone()).collect()
+    }
+
+    #[test]
+    fn test_history_keeps_most_recent() {
+        let mut queue = Queue::new();
+        for number in 0..HISTORY_LENGTH + 5 {
+            queue.push_history(Track {
+                title: number.to_string(),
+                ..Default::default()
+            });
+        }
+        assert_eq!(queue.history.len(), HISTORY_LENGTH);
+        assert_eq!(queue.history[0].title, "5");
+        assert_eq!(
+            queue.history.last().unwrap().title,
+            (HISTORY_LENGTH + 4).to_string()
+        );
+    }
+
+    #[test]
+    fn test_shuffle_off_restores_order() {
+        let mut queue = Queue::new();
+        for title in ["a", "b", "c", "d", "e", "f"] {
+            queue.add_track(track(title, title));
+        }
+        queue.set_shuffle(ShuffleMode::Random);
+        let played = queue.pop_next().unwrap();
+        assert_eq!(queue.get_tracks().len(), 5);
+
+        queue.set_shuffle(ShuffleMode::Off);
+        let mut expected = vec!["a", "b", "c", "d", "e", "f"];
+        expected.retain(|&t| t != played.title);
+        assert_eq!(titles(&queue.get_tracks()), expected);
+    }
+
+    #[test]
+    fn test_smart_shuffle_separates_artists() {
+        let mut queue = Queue::new();
+        for number in 0..10 {
+            let artist = if number % 2 == 0 { "Even" } else { "Odd" };
+            queue.add_track(track(&number.to_string(), artist));
+        }
+        queue.set_shuffle(ShuffleMode::Smart);
+        queue.add_track(track("10", "Even"));
+
+        let tracks = queue.get_tracks();
+        assert_eq!(tracks.len(), 11);
+        for pair in tracks.windows(2) {
+            assert_ne!(pair[0].artist, pair[1].artist);
+        }
+    }
+
+    #[test]
+    fn test_repeat_all_cycles() {
+        let mut queue = Queue::new();
+        queue.add_track(track("a", "A"));
+        queue.add_track(track("b", "B"));
+        queue.repeat = RepeatMode::All;
+
+        let played: Vec<String> = (0..5).map(|_| queue.pop_next().unwrap().title).collect();
+        assert_eq!(played, ["a", "b", "a", "b", "a"]);
+        assert_eq!(queue.tracks.len(), 2);
+    }
+
+    #[test]
+    fn test_insert_next_while_shuffled() {
+        let mut queue = Queue::new();
+        queue.add_track(track("a", "A"));
+        queue.add_track(track("b", "B"));
+        queue.set_shuffle(ShuffleMode::Random);
+        queue.insert(0, track("c", "C"));
+        assert_eq!(queue.peek().unwrap().title, "c");
+        assert_eq!(queue.get_tracks().len(), 3);
+    }
+
+    #[test]
+    fn test_move_and_remove_by_position() {
+        let mut queue = Queue::new();
+        queue.add_tracks(vec![track("a", "A"), track("b", "B"), track("a", "A")]);
+        queue.move_track(0, 2);
+        assert_eq!(titles(&queue.get_tracks()), ["b", "a", "a"]);
+
+        // Only the entry at the position goes, not every copy of the track
+        assert_eq!(queue.remove(1).unwrap().title, "a");
+        assert_eq!(titles(&queue.get_tracks()), ["b", "a"]);
+    }
+
+    #[test]
+    fn test_move_while_shuffled_keeps_added_order() {
+        let mut queue = Queue::new();
+        queue.add_tracks(vec![track("a", "A"), track("b", "B"), track("c", "C")]);
+        queue.set_shuffle(ShuffleMode::Random);
+        let played = queue.get_tracks();
+        queue.move_track(2, 0);
+        assert_eq!(queue.get_tracks()[0].title, played[2].title);
+
+        queue.set_shuffle(ShuffleMode::Off);
+        assert_eq!(titles(&queue.get_tracks()), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_move_out_of_range() {
+        let mut queue = Queue::new();
+        queue.add_tracks(vec![track("a", "A"), track("b", "B"), track("c", "C")]);
+        let revision = queue.revision();
+        queue.move_track(3, 0);
+        assert_eq!(titles(&queue.get_tracks()), ["a", "b", "c"]);
+        assert_eq!(queue.revision(), revision);
+
+        queue.move_track(0, 5);
+        assert_eq!(titles(&queue.get_tracks()), ["b", "c", "a"]);
+        assert_ne!(queue.revision(), revision);
+    }
+
+    #[test]
+    fn test_remove_and_insert_out_of_range() {
+        let mut queue = Queue::new();
+        queue.add_tracks(vec![track("a", "A"), track("b", "B")]);
+        assert!(queue.remove(2).is_none());
+        queue.insert(5, track("c", "C"));
+        assert_eq!(titles(&queue.get_tracks()), ["a", "b", "c"]);
+
+        queue.set_shuffle(ShuffleMode::Random);
+        assert!(queue.remove(3).is_none());
+        queue.insert(9, track("d", "D"));
+        assert_eq!(queue.get_tracks()[3].title, "d");
+    }
+
+    #[test]
+    fn test_passes_over_failed_tracks() {
+        let mut queue = Queue::new();
+        let tracks = ["a", "b", "c"].map(|title| Track {
+            id: Some(TrackId(title.as_bytes()[0] as i64)),
+            ..track(title, "A")
+        });
+        queue.add_tracks(tracks.to_vec());
+        queue.mark_failed(tracks[0].id.unwrap());
+        assert_eq!(queue.peek().unwrap().title, "b");
+        assert_eq!(queue.pop_next().unwrap().title, "b");
+        // Still queued, and played once retried
+        assert_eq!(titles(&queue.get_tracks()), ["a", "c"]);
+        queue.retry(tracks[0].id.unwrap());
+        assert_eq!(queue.pop_next().unwrap().title, "a");
     }
 }
@@ -0,0 +1,362 @@
+use crate::audio_track::{Track, TrackId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::f32::consts::PI;
+
+pub const MIN_TEMPO: f32 = 0.5;
+pub const MAX_TEMPO: f32 = 3.0;
+/// The furthest the pitch can be shifted either way, in semitones.
+pub const MAX_PITCH: f32 = 12.0;
+
+// The length of the pieces the audio is cut into, short enough not to smear
+// speech and long enough to hold a period of a low voice.
+const WINDOW_SECONDS: f32 = 0.03;
+// How far a piece may be moved from where it should come from to line it up
+// with the one before it.
+const SEARCH_SECONDS: f32 = 0.01;
+// Only every this many frames are compared when lining pieces up.
+const COMPARE_STEP: usize = 4;
+
+/// How fast a track plays and how far its pitch is shifted. The two are
+/// independent: speeding up keeps the pitch unless it is shifted as well.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct Speed {
+    pub tempo: f32,
+    /// In semitones.
+    pub pitch: f32,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Self {
+            tempo: 1.0,
+            pitch: 0.0,
+        }
+    }
+}
+
+impl Speed {
+    /// The factor the pitch is shifted by.
+    pub fn pitch_ratio(&self) -> f64 {
+        2f64.powf(self.pitch as f64 / 12.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct SpeedSettings {
+    /// The speed of tracks that haven't got one of their own.
+    pub default: Speed,
+    /// Keeps the speed set while a track plays for that track alone, for
+    /// podcasts and lessons that each want their own.
+    pub per_track: bool,
+    pub tracks: BTreeMap<TrackId, Speed>,
+}
+
+impl SpeedSettings {
+    pub fn speed_for(&self, track: &Track) -> Speed {
+        let own = track.id.and_then(|id| self.tracks.get(&id));
+        match own {
+            Some(speed) if self.per_track => *speed,
+            _ => self.default,
+        }
+    }
+
+    /// Sets the speed of `track` when speeds are kept per track, and the
+    /// default speed otherwise.
+    pub fn set_speed_for(&mut self, track: Option<&Track>, speed: Speed) {
+        match track.and_then(|t| t.id).filter(|_| self.per_track) {
+            Some(id) if speed == self.default => {
+                self.tracks.remove(&id);
+            }
+            Some(id) => {
+                self.tracks.insert(id, speed);
+            }
+            None => self.default = speed,
+        }
+    }
+}
+
+/// Changes the tempo of interleaved audio without changing its pitch, by
+/// waveform similarity overlap-add (WSOLA). The input is cut into windowed
+/// pieces that are laid over each other at a steady pace; each piece is taken
+/// from about where the tempo says, moved a little to where it best continues
+/// the piece before it, so the waveforms line up without clicks.
+pub struct TimeStretch {
+    channels: usize,
+    window: Vec<f32>,
+    search: usize,
+    tempo: f64,
+    input: Vec<f32>,
+    // The frame `input` starts at, counted from the start of the input.
+    input_start: usize,
+    // Where the next piece should come from at the current tempo.
+    ideal: f64,
+    // Where the last piece came from.
+    previous: Option<usize>,
+    // The output the last piece was added to. Its first half is complete.
+    overlap: Vec<f32>,
+    ready: Vec<f32>,
+    ready_offset: usize,
+    finished: bool,
+    drained: bool,
+}
+
+impl TimeStretch {
+    pub fn new(sample_rate: u32, channels: u16) -> TimeStretch {
+        let channels = channels.max(1) as usize;
+        // Even, so pieces overlap by exactly half
+        let length = ((sample_rate as f32 * WINDOW_SECONDS) as usize / 2 * 2).max(16);
+        // A periodic Hann window, which adds up to one when half overlapped
+        let window = (0..length)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / length as f32).cos())
+            .collect();
+        TimeStretch {
+            channels,
+            window,
+            search: (sample_rate as f32 * SEARCH_SECONDS) as usize,
+            tempo: 1.0,
+            input: Vec::new(),
+            input_start: 0,
+            ideal: 0.0,
+            previous: None,
+            overlap: vec![0.0; length * channels],
+            ready: Vec::new(),
+            ready_offset: 0,
+            finished: false,
+            drained: false,
+        }
+    }
+
+    pub fn set_tempo(&mut self, tempo: f64) {
+        self.tempo = tempo;
+    }
+
+    /// Whether audio has to go through here. At the normal tempo it is
+    /// passed by, until audio has gone in at another tempo; from then on it
+    /// keeps going through until `reset`, so none of it is lost or repeated.
+    pub fn is_active(&self) -> bool {
+        self.tempo != 1.0 || !self.input.is_empty() || self.previous.is_some() || self.finished
+    }
+
+    /// Forgets all audio, for when the input jumps elsewhere.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.input_start = 0;
+        self.ideal = 0.0;
+        self.previous = None;
+        self.overlap.fill(0.0);
+        self.ready.clear();
+        self.ready_offset = 0;
+        self.finished = false;
+        self.drained = false;
+    }
+
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        self.input.extend_from_slice(&frame[..self.channels]);
+    }
+
+    /// Marks the end of the input, so what is left of it is played out.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.drained && self.ready_offset == self.ready.len()
+    }
+
+    /// Takes the next output frame. Returns false when more input is needed
+    /// first, or once everything has been played out after `finish`.
+    pub fn pop_frame(&mut self, output: &mut [f32]) -> bool {
+        loop {
+            if self.ready_offset < self.ready.len() {
+                let frame = &self.ready[self.ready_offset..self.ready_offset + self.channels];
+                output[..self.channels].copy_from_slice(frame);
+                self.ready_offset += self.channels;
+                return true;
+            }
+            if self.drained || !self.has_enough_input() {
+                return false;
+            }
+            self.add_piece();
+        }
+    }
+
+    fn input_end(&self) -> usize {
+        self.input_start + self.input.len() / self.channels
+    }
+
+    fn has_enough_input(&self) -> bool {
+        self.finished || self.input_end() >= self.ideal as usize + self.search + self.window.len()
+    }
+
+    // The mono mix of an input frame, silence outside the input kept.
+    fn mono(&self, frame: usize) -> f32 {
+        match frame.checked_sub(self.input_start) {
+            Some(index) if frame < self.input_end() => {
+                let start = index * self.channels;
+                self.input[start..start + self.channels].iter().sum()
+            }
+            _ => 0.0,
+        }
+    }
+
+    // Where around `ideal` the next piece best continues the previous one:
+    // the start whose first half looks most like what followed the previous
+    // piece in the input.
+    fn best_start(&self, ideal: usize, previous: usize) -> usize {
+        let hop = self.window.len() / 2;
+        let follows: Vec<f32> = (0..hop)
+            .step_by(COMPARE_STEP)
+            .map(|i| self.mono(previous + hop + i))
+            .collect();
+        let first = ideal.saturating_sub(self.search).max(self.input_start);
+        let last = ideal + self.search;
+        let mut best = (ideal, f32::MIN);
+        for start in first..=last {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            for (i, follow) in follows.iter().enumerate() {
+                let sample = self.mono(start + i * COMPARE_STEP);
+                correlation += follow * sample;
+                energy += sample * sample;
+            }
+            let score = correlation / energy.max(1e-9).sqrt();
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+        best.0
+    }
+
+    fn add_piece(&mut self) {
+        let length = self.window.len();
+        let hop = length / 2;
+        let ideal = self.ideal.round() as usize;
+
+        if self.finished && ideal >= self.input_end() {
+            // Play out the second half of the last piece
+            self.ready = self.overlap[..hop * self.channels].to_vec();
+            self.ready_offset = 0;
+            self.drained = true;
+            return;
+        }
+
+        let start = match self.previous {
+            Some(previous) => self.best_start(ideal, previous),
+            None => ideal,
+        };
+        for (i, weight) in self.window.iter().enumerate() {
+            let Some(index) = (start + i).checked_sub(self.input_start) else {
+                continue;
+            };
+            let offset = index * self.channels;
+            for channel in 0..self.channels {
+                let sample = self.input.get(offset + channel).copied().unwrap_or(0.0);
+                self.overlap[i * self.channels + channel] += sample * weight;
+            }
+        }
+
+        self.ready.clear();
+        self.ready
+            .extend_from_slice(&self.overlap[..hop * self.channels]);
+        self.ready_offset = 0;
+        self.overlap.copy_within(hop * self.channels.., 0);
+        self.overlap[(length - hop) * self.channels..].fill(0.0);
+
+        self.previous = Some(start);
+        self.ideal += hop as f64 * self.tempo;
+
+        // Drop the input neither the next piece nor the comparison needs
+        let keep_from = (start + hop).min((self.ideal as usize).saturating_sub(self.search));
+        if keep_from > self.input_start {
+            let drop = (keep_from - self.input_start).min(self.input.len() / self.channels);
+            self.input.drain(..drop * self.channels);
+            self.input_start += drop;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    // Runs a sine through the stretcher and returns what comes out.
+    fn stretch_sine(frequency: f32, seconds: f32, tempo: f64) -> Vec<f32> {
+        let mut stretch = TimeStretch::new(RATE, 1);
+        stretch.set_tempo(tempo);
+        let mut output = Vec::new();
+        let mut frame = [0.0];
+        for i in 0..(RATE as f32 * seconds) as usize {
+            stretch.push_frame(&[(2.0 * PI * frequency * i as f32 / RATE as f32).sin()]);
+            while stretch.pop_frame(&mut frame) {
+                output.push(frame[0]);
+            }
+        }
+        stretch.finish();
+        while stretch.pop_frame(&mut frame) {
+            output.push(frame[0]);
+        }
+        assert!(stretch.is_finished());
+        output
+    }
+
+    fn zero_crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn test_tempo_changes_length() {
+        for tempo in [0.5, 2.0] {
+            let output = stretch_sine(220.0, 2.0, tempo);
+            let expected = 2.0 * RATE as f64 / tempo;
+            let difference = (output.len() as f64 - expected).abs();
+            assert!(
+                difference < RATE as f64 * 0.05,
+                "{} {}",
+                tempo,
+                output.len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_tempo_keeps_pitch() {
+        let output = stretch_sine(220.0, 2.0, 1.5);
+        // Leave out the fade in and out at the ends
+        let middle = &output[RATE as usize / 10..output.len() - RATE as usize / 10];
+        let frequency = zero_crossings(middle) as f32 / 2.0 / (middle.len() as f32 / RATE as f32);
+        assert!((frequency - 220.0).abs() < 5.0, "{}", frequency);
+    }
+
+    #[test]
+    fn test_speed_per_track() {
+        let track = Track {
+            id: Some(TrackId(1)),
+            ..Default::default()
+        };
+        let other = Track {
+            id: Some(TrackId(2)),
+            ..Default::default()
+        };
+        let fast = Speed {
+            tempo: 1.5,
+            pitch: 0.0,
+        };
+        let mut settings = SpeedSettings {
+            per_track: true,
+            ..Default::default()
+        };
+        settings.set_speed_for(Some(&track), fast);
+        assert_eq!(settings.speed_for(&track), fast);
+        assert_eq!(settings.speed_for(&other), Speed::default());
+
+        settings.per_track = false;
+        assert_eq!(settings.speed_for(&track), Speed::default());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::loudness;
+use symphonia::core::meta::{MetadataRevision, StandardTagKey, Tag};
+use symphonia::core::probe::ProbeResult;
+
+/// The embedded tags Rustify cares about. Symphonia maps ID3v2 frames, Vorbis
+/// comments, MP4 atoms and RIFF INFO chunks onto the same standard keys, so
+/// this works the same for every container.
+#[derive(Default, Debug, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    /// ReplayGain in dB and peaks as a fraction of full scale.
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl Tags {
+    fn read_revision(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            let value = value.trim();
+            if value.is_empty() || self.read_gain(tag, value) {
+                continue;
+            }
+            let field = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                Some(StandardTagKey::Artist) => &mut self.artist,
+                Some(StandardTagKey::Album) => &mut self.album,
+                Some(StandardTagKey::AlbumArtist) => &mut self.album_artist,
+                Some(StandardTagKey::Genre) => &mut self.genre,
+                Some(StandardTagKey::Composer) => &mut self.composer,
+                Some(StandardTagKey::TrackNumber) => {
+                    self.track_number = self.track_number.or(parse_number(value));
+                    continue;
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    self.disc_number = self.disc_number.or(parse_number(value));
+                    continue;
+                }
+                Some(StandardTagKey::Date) | Some(StandardTagKey::OriginalDate) => {
+                    self.year = self.year.or(parse_year(value));
+                    continue;
+                }
+                _ => continue,
+            };
+            if field.is_none() {
+                *field = Some(value.to_string());
+            }
+        }
+    }
+
+    // Reads a ReplayGain or Opus R128 tag, returning false for any other tag.
+    // ReplayGain in ID3v2 TXXX frames and APE tags doesn't get a standard key,
+    // so the raw key is checked too.
+    fn read_gain(&mut self, tag: &Tag, value: &str) -> bool {
+        let key = tag.key.to_ascii_lowercase();
+        let is = |std_key: StandardTagKey, name: &str| {
+            tag.std_key == Some(std_key) || key.ends_with(name)
+        };
+        let (field, value) = if is(StandardTagKey::ReplayGainTrackGain, "replaygain_track_gain") {
+            (&mut self.track_gain, parse_gain(value))
+        } else if is(StandardTagKey::ReplayGainTrackPeak, "replaygain_track_peak") {
+            (&mut self.track_peak, parse_gain(value))
+        } else if is(StandardTagKey::ReplayGainAlbumGain, "replaygain_album_gain") {
+            (&mut self.album_gain, parse_gain(value))
+        } else if is(StandardTagKey::ReplayGainAlbumPeak, "replaygain_album_peak") {
+            (&mut self.album_peak, parse_gain(value))
+        } else if key == "r128_track_gain" {
+            (&mut self.track_gain, parse_r128_gain(value))
+        } else if key == "r128_album_gain" {
+            (&mut self.album_gain, parse_r128_gain(value))
+        } else {
+            return false;
+        };
+        if field.is_none() {
+            *field = value;
+        }
+        true
+    }
+}
+
+/// Reads the tags of a probed audio file. Files without tags give empty
+/// `Tags`.
+pub fn read_tags(probed: &mut ProbeResult) -> Tags {
+    let mut tags = Tags::default();
+
+    // Tags stored in the container take priority over ones found while
+    // probing, such as an ID3v2 block in front of a FLAC stream.
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.read_revision(revision);
+    }
+    if let Some(mut metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.skip_to_latest() {
+            tags.read_revision(revision);
+        }
+    }
+    tags
+}
+
+// Track and disc numbers are often stored as "3/12".
+fn parse_number(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+// Dates can be anything from "1999" to "1999-04-12T00:00:00".
+fn parse_year(value: &str) -> Option<u32> {
+    let year: String = value.chars().take_while(char::is_ascii_digit).collect();
+    if year.len() == 4 {
+        year.parse().ok()
+    } else {
+        None
+    }
+}
+
+// Gains are written as "-6.54 dB", peaks as a plain number.
+fn parse_gain(value: &str) -> Option<f32> {
+    let number = value.trim_end_matches(|c: char| c.is_ascii_alphabetic() || c.is_whitespace());
+    number
+        .trim()
+        .parse()
+        .ok()
+        .filter(|gain: &f32| gain.is_finite())
+}
+
+// Opus stores gains in 1/256 dB, relative to EBU R128's reference level
+// rather than ReplayGain's.
+fn parse_r128_gain(value: &str) -> Option<f32> {
+    let steps: i32 = value.trim().parse().ok()?;
+    Some(steps as f32 / 256.0 + loudness::REFERENCE_LUFS - loudness::R128_REFERENCE_LUFS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("3"), Some(3));
+        assert_eq!(parse_number("3/12"), Some(3));
+        assert_eq!(parse_number("side A"), None);
+    }
+
+    #[test]
+    fn test_parse_year() {
+        assert_eq!(parse_year("1999"), Some(1999));
+        assert_eq!(parse_year("1999-04-12T00:00:00"), Some(1999));
+        assert_eq!(parse_year("99"), None);
+    }
+
+    #[test]
+    fn test_parse_gain() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain("+2.00 dB"), Some(2.0));
+        assert_eq!(parse_gain("0.988547"), Some(0.988547));
+        assert_eq!(parse_gain("loud"), None);
+        assert_eq!(parse_r128_gain("-1280"), Some(0.0));
+    }
+
+    #[test]
+    fn test_read_tags_untagged() {
+        let mut probed = crate::decoder::probe("assets/tracks/CantinaBand60.wav").unwrap();
+        assert_eq!(read_tags(&mut probed), Tags::default());
+    }
+}
@@ -1,6 +1,16 @@
 use crate::audio_thread::AudioCommand;
 use crate::audio_thread::AudioState;
+use crate::audio_track;
+use crate::library_thread::LibraryCommand;
+use crate::player::LoopRegion;
+use crate::queue::{RepeatMode, ShuffleMode};
+use crate::speed::{Speed, MAX_PITCH, MAX_TEMPO, MIN_TEMPO};
 use crate::TemplateApp;
+use std::time::Duration;
+
+const SEEK_BAR_HEIGHT: f32 = 40.0;
+// The width of each bar of the waveform and the gap after it, in points.
+const SEEK_BAR_STEP: f32 = 3.0;
 
 pub fn show_bottom_panel(ctx: &egui::Context, app: &mut TemplateApp) {
     ctx.request_repaint();
@@ -11,10 +21,19 @@ pub fn show_bottom_panel(ctx: &egui::Context, app: &mut TemplateApp) {
 
             // Volume slider
             ui.label("Volume:"); // Optionally, add a label for clarity
-            ui.add(egui::Slider::new(&mut app.volume, 0.0..=1.0));
-            app.audio_thread_sender
-                .send(AudioCommand::SetVolume(app.volume))
-                .unwrap();
+            if ui
+                .add(egui::Slider::new(&mut app.volume, 0.0..=1.0))
+                .changed()
+            {
+                app.send_audio(AudioCommand::SetVolume(app.volume));
+            }
+
+            ui.separator();
+
+            if ui.button("⏮").clicked() {
+                let previous = app.queue.history.last().cloned();
+                app.send_audio(AudioCommand::Previous(previous));
+            }
 
             ui.separator();
 
@@ -25,64 +44,361 @@ pub fn show_bottom_panel(ctx: &egui::Context, app: &mut TemplateApp) {
             };
 
             if ui.button(button_label).clicked() {
-                let (state_sender, state_receiver) = std::sync::mpsc::channel();
-                app.audio_thread_sender
-                    .send(AudioCommand::GetState(state_sender))
-                    .unwrap();
-
-                match state_receiver.recv() {
-                    Ok(AudioState::Playing) => {
-                        app.audio_thread_sender.send(AudioCommand::Pause).unwrap();
+                match app.audio_state {
+                    AudioState::Playing => {
+                        app.send_audio(AudioCommand::Pause);
                     }
-                    Ok(AudioState::Paused) => {
-                        app.audio_thread_sender.send(AudioCommand::Play).unwrap();
+                    AudioState::Paused => {
+                        app.send_audio(AudioCommand::Play);
                     }
-                    _ => (),
+                    AudioState::Empty => (),
                 }
             }
 
             ui.separator();
 
             if ui.button("⏭").clicked() {
-                app.audio_thread_sender.send(AudioCommand::Skip).unwrap();
+                // On repeat one the current track is lined up next, skipping
+                // moves on to the queue instead
+                if app.queue.repeat == RepeatMode::One {
+                    let next_track = app.queue.peek().cloned();
+                    app.next_track = next_track.clone();
+                    app.send_audio(AudioCommand::SetNext(next_track));
+                }
+                app.send_audio(AudioCommand::Skip);
+            }
+
+            ui.separator();
+
+            egui::ComboBox::from_id_source("repeat_mode")
+                .selected_text(app.queue.repeat.to_string())
+                .show_ui(ui, |ui| {
+                    for mode in RepeatMode::ALL {
+                        ui.selectable_value(&mut app.queue.repeat, mode, mode.to_string());
+                    }
+                });
+
+            let mut shuffle = app.queue.shuffle();
+            egui::ComboBox::from_id_source("shuffle_mode")
+                .selected_text(shuffle.to_string())
+                .show_ui(ui, |ui| {
+                    for mode in ShuffleMode::ALL {
+                        ui.selectable_value(&mut shuffle, mode, mode.to_string());
+                    }
+                });
+            if shuffle != app.queue.shuffle() {
+                app.queue.set_shuffle(shuffle);
             }
 
             ui.separator();
 
-            let (progress_sender, progress_reciever) = std::sync::mpsc::channel();
-            app.audio_thread_sender
-                .send(AudioCommand::GetProgress(progress_sender))
-                .unwrap();
+            show_loop_controls(ui, app);
+            show_bookmarks_menu(ui, app);
 
-            let progress = progress_reciever.recv().unwrap();
-            // Convert progress from a Duration to a f32
-            app.track_progress = progress.as_secs_f32();
+            ui.separator();
 
-            let (duration_sender, duration_reciever) = std::sync::mpsc::channel();
-            app.audio_thread_sender
-                .send(AudioCommand::GetTrackDuration(duration_sender))
-                .unwrap();
+            show_speed_controls(ui, app);
+
+            if app.no_output_device {
+                ui.separator();
+                ui.colored_label(egui::Color32::RED, "No audio output device");
+            }
+        });
+    });
 
-            let duration = duration_reciever.recv().unwrap();
-            app.track_duration = duration.as_secs_f32();
+    // Added after the controls, so it sits above them
+    egui::TopBottomPanel::bottom("seek_bar").show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.label(audio_track::format_duration(Duration::from_secs_f32(
+                app.track_progress,
+            )));
+            let duration =
+                audio_track::format_duration(Duration::from_secs_f32(app.track_duration));
+            // Leave room for the duration after the bar
+            let width = ui.available_width() - ui.spacing().interact_size.x;
+            show_seek_bar(ui, app, width);
+            ui.label(duration);
+        });
+    });
+}
 
-            if ui
-                .add(
-                    egui::Slider::new(&mut app.track_progress, 0.0..=app.track_duration)
-                        .text("Track Progress"),
-                )
-                .changed()
-            {
-                // if no current track do nothing
-                if app.current_track.is_none() {
-                    return;
+// The current track's waveform, brighter where it has been played. Clicking
+// anywhere on it seeks there, dragging seeks where it is let go, and
+// right-clicking sets loop points there. The loop is shaded behind the
+// waveform. Until the waveform is drawn it is a flat line.
+fn show_seek_bar(ui: &mut egui::Ui, app: &mut TemplateApp, width: f32) {
+    let size = egui::vec2(width.max(0.0), SEEK_BAR_HEIGHT);
+    let sense = if app.track_duration > 0.0 {
+        egui::Sense::click_and_drag()
+    } else {
+        egui::Sense::hover()
+    };
+    let (rect, response) = ui.allocate_exact_size(size, sense);
+
+    let pointed = response.interact_pointer_pos().map(|pointer| {
+        let fraction = ((pointer.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+        fraction * app.track_duration
+    });
+    let seek_to = if response.drag_released() {
+        app.seek_preview.take().or(pointed)
+    } else if response.dragged() {
+        app.seek_preview = pointed.or(app.seek_preview);
+        None
+    } else {
+        app.seek_preview = None;
+        pointed.filter(|_| response.clicked())
+    };
+    if let Some(progress) = seek_to {
+        app.track_progress = progress;
+        app.send_audio(AudioCommand::SetProgress(progress));
+    }
+    if response.secondary_clicked() {
+        app.seek_menu_at = pointed;
+    }
+    let response = response.context_menu(|ui| show_seek_menu(ui, app));
+
+    let shown = app.seek_preview.unwrap_or(app.track_progress);
+    let played = if app.track_duration > 0.0 {
+        rect.left() + shown / app.track_duration * rect.width()
+    } else {
+        rect.left()
+    };
+    let played_color = ui.visuals().selection.bg_fill;
+    let unplayed_color = ui.visuals().widgets.inactive.bg_fill;
+    let painter = ui.painter_at(rect);
+
+    // The loop goes under the waveform, or is just a line while only A is set
+    let loop_x = |at: f32| rect.left() + at / app.track_duration * rect.width();
+    match (app.loop_start, app.loop_end) {
+        (Some(start), Some(end)) if app.track_duration > 0.0 => {
+            let span = egui::Rect::from_x_y_ranges(loop_x(start)..=loop_x(end), rect.y_range());
+            painter.rect_filled(span, 0.0, played_color.gamma_multiply(0.3));
+            painter.vline(loop_x(start), rect.y_range(), ui.visuals().selection.stroke);
+            painter.vline(loop_x(end), rect.y_range(), ui.visuals().selection.stroke);
+        }
+        (Some(start), None) if app.track_duration > 0.0 => {
+            painter.vline(loop_x(start), rect.y_range(), ui.visuals().selection.stroke);
+        }
+        _ => {}
+    }
+
+    let waveform = app
+        .waveform
+        .as_ref()
+        .map(|(_, waveform)| waveform)
+        .filter(|waveform| !waveform.columns.is_empty());
+    let center = rect.center().y;
+    match waveform {
+        Some(waveform) => {
+            // Quiet tracks are scaled up to fill the bar
+            let scale = rect.height() / 2.0 / waveform.loudest().max(0.01);
+            let columns = &waveform.columns;
+            let bars = (rect.width() / SEEK_BAR_STEP) as usize;
+            for bar in 0..bars {
+                let x = rect.left() + bar as f32 * SEEK_BAR_STEP;
+                let [min, max] = columns[bar * columns.len() / bars];
+                let top = center - max * scale;
+                let bottom = (center - min * scale).max(top + 1.0);
+                let color = if x < played {
+                    played_color
+                } else {
+                    unplayed_color
+                };
+                let bar = egui::Rect::from_x_y_ranges(x..=x + SEEK_BAR_STEP - 1.0, top..=bottom);
+                painter.rect_filled(bar, 0.0, color);
+            }
+        }
+        None => {
+            let line = |left: f32, right: f32, color| {
+                let line = egui::Rect::from_x_y_ranges(left..=right, center - 1.0..=center + 1.0);
+                painter.rect_filled(line, 0.0, color);
+            };
+            line(rect.left(), played, played_color);
+            line(played, rect.right(), unplayed_color);
+        }
+    }
+
+    if let Some(hover) = response.hover_pos() {
+        painter.vline(hover.x, rect.y_range(), ui.visuals().selection.stroke);
+    }
+}
+
+// Opened by right-clicking the seek bar, to put the loop points where it was
+// clicked.
+fn show_seek_menu(ui: &mut egui::Ui, app: &mut TemplateApp) {
+    let Some(at) = app.seek_menu_at else {
+        ui.close_menu();
+        return;
+    };
+    ui.label(audio_track::format_duration(Duration::from_secs_f32(at)));
+    ui.separator();
+    if ui.button("Start loop here").clicked() {
+        set_loop_start(app, at);
+        ui.close_menu();
+    }
+    let can_end = app.loop_start.is_some_and(|start| at > start);
+    if ui
+        .add_enabled(can_end, egui::Button::new("End loop here"))
+        .clicked()
+    {
+        set_loop_end(app, at);
+        ui.close_menu();
+    }
+    if ui
+        .add_enabled(app.loop_start.is_some(), egui::Button::new("Stop looping"))
+        .clicked()
+    {
+        clear_loop(app);
+        ui.close_menu();
+    }
+}
+
+// Tempo and pitch of the current track, or of every track unless speeds are
+// kept per track.
+fn show_speed_controls(ui: &mut egui::Ui, app: &mut TemplateApp) {
+    let mut speed = match &app.current_track {
+        Some(track) => app.speed.speed_for(track),
+        None => app.speed.default,
+    };
+    let mut changed = ui
+        .add(
+            egui::Slider::new(&mut speed.tempo, MIN_TEMPO..=MAX_TEMPO)
+                .step_by(0.05)
+                .suffix("×")
+                .text("Speed"),
+        )
+        .changed();
+    changed |= ui
+        .add(
+            egui::Slider::new(&mut speed.pitch, -MAX_PITCH..=MAX_PITCH)
+                .step_by(0.5)
+                .suffix(" st")
+                .text("Pitch"),
+        )
+        .changed();
+    if ui
+        .add_enabled(speed != Speed::default(), egui::Button::new("Reset"))
+        .clicked()
+    {
+        speed = Speed::default();
+        changed = true;
+    }
+
+    if changed {
+        app.speed.set_speed_for(app.current_track.as_ref(), speed);
+        app.send_audio(AudioCommand::SetSpeed(app.speed.clone()));
+    }
+}
+
+// Sets the A and B points of a loop at the current position. The section
+// between them plays over and over once both are set. They can also be set
+// from the seek bar's menu.
+fn show_loop_controls(ui: &mut egui::Ui, app: &mut TemplateApp) {
+    if ui
+        .selectable_label(app.loop_start.is_some(), "A")
+        .on_hover_text("Start the loop here")
+        .clicked()
+    {
+        set_loop_start(app, app.track_progress);
+    }
+    let can_end = app
+        .loop_start
+        .is_some_and(|start| app.track_progress > start);
+    if ui
+        .add_enabled(
+            can_end,
+            egui::SelectableLabel::new(app.loop_end.is_some(), "B"),
+        )
+        .on_hover_text("End the loop here")
+        .clicked()
+    {
+        set_loop_end(app, app.track_progress);
+    }
+    if let (Some(start), Some(end)) = (app.loop_start, app.loop_end) {
+        ui.label(format!(
+            "🔁 {} – {}",
+            audio_track::format_duration(Duration::from_secs_f32(start)),
+            audio_track::format_duration(Duration::from_secs_f32(end))
+        ));
+    }
+    if app.loop_start.is_some() && ui.button("✕").on_hover_text("Stop looping").clicked() {
+        clear_loop(app);
+    }
+}
+
+// Moves A, dropping B if it is no longer after it.
+fn set_loop_start(app: &mut TemplateApp, at: f32) {
+    app.loop_start = Some(at);
+    app.loop_end = app.loop_end.filter(|&end| end > at);
+    send_loop(app);
+}
+
+fn set_loop_end(app: &mut TemplateApp, at: f32) {
+    if app.loop_start.is_some_and(|start| at > start) {
+        app.loop_end = Some(at);
+        send_loop(app);
+    }
+}
+
+fn clear_loop(app: &mut TemplateApp) {
+    app.loop_start = None;
+    app.loop_end = None;
+    send_loop(app);
+}
+
+fn send_loop(app: &mut TemplateApp) {
+    let region = match (app.loop_start, app.loop_end) {
+        (Some(start), Some(end)) => Some(LoopRegion {
+            start: Duration::from_secs_f32(start),
+            end: Duration::from_secs_f32(end),
+        }),
+        _ => None,
+    };
+    app.send_audio(AudioCommand::SetLoop(region));
+}
+
+// The current track's bookmarks, to jump to them or add another at the
+// current position. Only tracks in the library can have bookmarks.
+fn show_bookmarks_menu(ui: &mut egui::Ui, app: &mut TemplateApp) {
+    let Some(track) = app.current_track.clone() else {
+        return;
+    };
+    let Some(id) = track.id else {
+        return;
+    };
+    ui.menu_button(format!("🔖 {}", track.bookmarks.len()), |ui| {
+        for bookmark in &track.bookmarks {
+            ui.horizontal(|ui| {
+                let label = format!(
+                    "{} ({})",
+                    bookmark.name,
+                    audio_track::format_duration(bookmark.position)
+                );
+                if ui.button(label).clicked() {
+                    app.send_audio(AudioCommand::SetProgress(bookmark.position.as_secs_f32()));
+                    ui.close_menu();
+                }
+                if ui.button("🗑").clicked() {
+                    app.send_library(LibraryCommand::RemoveBookmark(bookmark.id));
+                }
+            });
+        }
+        if !track.bookmarks.is_empty() {
+            ui.separator();
+        }
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut app.new_bookmark_name).hint_text("Name"));
+            if ui.button("Add Bookmark").clicked() {
+                let position = Duration::from_secs_f32(app.track_progress);
+                let mut name = std::mem::take(&mut app.new_bookmark_name);
+                if name.is_empty() {
+                    name = audio_track::format_duration(position);
                 }
-                app.audio_thread_sender
-                    .send(AudioCommand::SetProgress(
-                        app.track_progress,
-                        app.current_track.clone().unwrap(),
-                    ))
-                    .unwrap();
+                app.send_library(LibraryCommand::AddBookmark {
+                    track: id,
+                    name,
+                    position,
+                });
             }
         });
     });
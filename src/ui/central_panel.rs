@@ -1,9 +1,24 @@
 use crate::audio_thread::AudioCommand;
+use crate::audio_thread::AudioState;
+use crate::audio_track::{self, Track, TrackId};
+use crate::equalizer::EqSettings;
+use crate::library_thread::LibraryCommand;
+use crate::playlist::EntryId;
+use crate::ui::now_playing;
+use crate::ui::queue_view;
 use crate::TemplateApp;
 use eframe::egui;
 
 pub fn show_central_panel(ctx: &egui::Context, app: &mut TemplateApp) {
     egui::CentralPanel::default().show(ctx, |ui| {
+        if app.show_queue {
+            queue_view::show_queue_view(ui, app);
+            return;
+        }
+        if app.show_now_playing {
+            now_playing::show_now_playing(ui, app);
+            return;
+        }
         ui.vertical(|ui| {
             // Determine the header text based on the current playlist
             let header_text = match &app.current_playlist {
@@ -11,44 +26,120 @@ pub fn show_central_panel(ctx: &egui::Context, app: &mut TemplateApp) {
                 None => "All Songs",
             };
             ui.heading(header_text);
+            ui.label(format!(
+                "{} tracks, {}",
+                app.track_list.len(),
+                audio_track::format_duration(audio_track::total_duration(&app.track_list))
+            ));
 
             ui.separator();
 
+            // In a playlist every row is one of its entries, so copies of the
+            // same track can be removed one at a time
+            let entries: Vec<Option<EntryId>> = match app
+                .current_playlist
+                .as_ref()
+                .and_then(|name| app.playlist_list.iter().find(|p| &p.name == name))
+            {
+                Some(playlist) => playlist.entries.iter().map(|e| Some(e.id)).collect(),
+                None => vec![None; app.track_list.len()],
+            };
+
             egui::ScrollArea::vertical().show(ui, |ui| {
-                for track in &app.track_list.clone() {
+                for (track, entry) in app.track_list.clone().iter().zip(entries) {
                     let button =
                         egui::Button::new(&track.title).fill(ui.style().visuals.window_fill());
 
-                    let response = ui.add(button);
+                    let response = ui
+                        .horizontal(|ui| {
+                            let playable = track.error.is_none() && !track.missing;
+                            let response = ui.add_enabled(playable, button);
+                            if let Some(duration) = track.duration {
+                                ui.label(audio_track::format_duration(duration));
+                            }
+                            if track.missing {
+                                ui.colored_label(egui::Color32::RED, "File missing");
+                            }
+                            if let Some(error) = &track.error {
+                                ui.colored_label(egui::Color32::RED, error);
+                            }
+                            ui.weak(track_details(track));
+                            if let Some(position) = resume_position(app, track) {
+                                ui.weak(format!(
+                                    "Resume at {}",
+                                    audio_track::format_duration(position)
+                                ));
+                            }
+                            response
+                        })
+                        .inner;
 
                     if response.clicked() {
-                        app.current_track = Some(track.file_path.clone());
-                        app.audio_thread_sender
-                            .send(AudioCommand::PlaySong(track.file_path.clone()))
-                            .unwrap();
+                        app.audio_state = AudioState::Playing;
+                        app.send_audio(AudioCommand::PlaySong(track.clone()));
                     }
 
                     response.context_menu(|ui| {
+                        if ui.button("Play Now (Replace Queue)").clicked() {
+                            app.queue.clear();
+                            app.queue_selection.clear();
+                            app.audio_state = AudioState::Playing;
+                            app.send_audio(AudioCommand::PlaySong(track.clone()));
+                            ui.close_menu();
+                        }
+                        if ui.button("Play Next").clicked() {
+                            app.queue.insert(0, track.clone());
+                            ui.close_menu();
+                        }
                         if ui.button("Add to Queue").clicked() {
                             app.queue.add_track(track.clone());
                             ui.close_menu();
                         }
+                        if track.album.is_some() && ui.button("Add Album to Queue").clicked() {
+                            app.queue
+                                .add_tracks(audio_track::album_tracks(&app.library_tracks, track));
+                            ui.close_menu();
+                        }
+                        if resume_position(app, track).is_some() {
+                            if ui.button("Start Over").clicked() {
+                                forget_resume_position(app, track);
+                                app.audio_state = AudioState::Playing;
+                                app.send_audio(AudioCommand::PlaySong(Track {
+                                    track_progress: None,
+                                    ..track.clone()
+                                }));
+                                ui.close_menu();
+                            }
+                            if ui.button("Mark as Finished").clicked() {
+                                forget_resume_position(app, track);
+                                ui.close_menu();
+                            }
+                        }
+                        // Only tracks in the library can have a preset of their own
+                        if let Some(id) = track.id {
+                            ui.menu_button("Equalizer Preset", |ui| {
+                                if show_track_preset_menu(ui, &mut app.eq_settings, id) {
+                                    let settings = app.eq_settings.clone();
+                                    app.send_audio(AudioCommand::SetEqualizer(settings));
+                                    ui.close_menu();
+                                }
+                            });
+                        }
                         // Condition to separate whether the song should be added or removed from
                         // the playlist
                         if app.current_playlist.is_some() {
                             if ui.button("Remove from Playlist").clicked() {
-                                if let Some(playlist_name) = &app.current_playlist {
+                                if let (Some(playlist_name), Some(entry)) =
+                                    (&app.current_playlist, entry)
+                                {
                                     if let Some(playlist) = app
                                         .playlist_list
-                                            .iter_mut()
-                                            .find(|playlist| playlist.name == *playlist_name)
-                                            {
-                                                // need to find some way to update the track list
-                                                playlist.remove_track(track.clone());
-                                                while let Some(index) = app.track_list.iter().position(|x| x == track) {
-                                                    app.track_list.remove(index);
-                                                }
-                                            }
+                                        .iter_mut()
+                                        .find(|playlist| playlist.name == *playlist_name)
+                                    {
+                                        playlist.remove_entry(entry);
+                                        app.track_list = playlist.tracks();
+                                    }
                                 }
                                 ui.close_menu();
                             }
@@ -69,3 +160,75 @@ pub fn show_central_panel(ctx: &egui::Context, app: &mut TemplateApp) {
         });
     });
 }
+
+// Where `track` picks up when played, if it is long enough to resume and
+// was left partway.
+fn resume_position(app: &TemplateApp, track: &Track) -> Option<std::time::Duration> {
+    track
+        .track_progress
+        .filter(|_| app.resume.applies_to(track))
+}
+
+// Has `track` start from the beginning the next time it is played.
+fn forget_resume_position(app: &mut TemplateApp, track: &Track) {
+    if let Some(id) = track.id {
+        app.send_library(LibraryCommand::SaveResumePosition {
+            track: id,
+            position: None,
+        });
+    }
+}
+
+// Lets the user pick a preset for `track` alone, or go back to the one of
+// its genre or the default. Returns whether the choice changed.
+fn show_track_preset_menu(ui: &mut egui::Ui, settings: &mut EqSettings, track: TrackId) -> bool {
+    let current = settings.track_presets.get(&track).cloned();
+    let mut selected = current.clone();
+    ui.radio_value(&mut selected, None, "Automatic");
+    for preset in &settings.presets {
+        ui.radio_value(&mut selected, Some(preset.name.clone()), &preset.name);
+    }
+    if selected == current {
+        return false;
+    }
+    match selected {
+        Some(name) => settings.track_presets.insert(track, name),
+        None => settings.track_presets.remove(&track),
+    };
+    true
+}
+
+// One line summary of a track's tags, e.g.
+// "Artist - Album (1999) - Disc 1, Track 3 - Genre - Composer: Name"
+fn track_details(track: &Track) -> String {
+    let mut details: Vec<String> = Vec::new();
+    if let Some(artist) = &track.artist {
+        details.push(artist.clone());
+    }
+    if let Some(album) = &track.album {
+        match track.year {
+            Some(year) => details.push(format!("{} ({})", album, year)),
+            None => details.push(album.clone()),
+        }
+    } else if let Some(year) = track.year {
+        details.push(year.to_string());
+    }
+    if let Some(album_artist) = &track.album_artist {
+        if track.artist.as_ref() != Some(album_artist) {
+            details.push(format!("Album artist: {}", album_artist));
+        }
+    }
+    match (track.disc_number, track.track_number) {
+        (Some(disc), Some(number)) => details.push(format!("Disc {}, Track {}", disc, number)),
+        (None, Some(number)) => details.push(format!("Track {}", number)),
+        (Some(disc), None) => details.push(format!("Disc {}", disc)),
+        (None, None) => (),
+    }
+    if let Some(genre) = &track.genre {
+        details.push(genre.clone());
+    }
+    if let Some(composer) = &track.composer {
+        details.push(format!("Composer: {}", composer));
+    }
+    details.join(" - ")
+}
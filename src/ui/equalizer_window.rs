@@ -0,0 +1,234 @@
+use crate::audio_thread::AudioCommand;
+use crate::equalizer::{Band, EqMode, EqSettings, MAX_GAIN_DB};
+use crate::TemplateApp;
+
+pub fn show_equalizer_window(ctx: &egui::Context, app: &mut TemplateApp) {
+    let mut open = app.show_equalizer;
+    let mut changed = false;
+
+    egui::Window::new("Equalizer")
+        .open(&mut open)
+        .resizable(true)
+        .show(ctx, |ui| {
+            let settings = &mut app.eq_settings;
+            changed |= ui.checkbox(&mut settings.enabled, "Enabled").changed();
+
+            ui.separator();
+
+            changed |= edit_presets(ui, settings, &mut app.new_eq_preset_name);
+
+            ui.separator();
+
+            let default_preset = settings.default_preset.clone();
+            if let Some(preset) = settings.preset_mut(&default_preset) {
+                ui.horizontal(|ui| {
+                    for mode in [EqMode::Graphic, EqMode::Parametric] {
+                        if ui
+                            .selectable_label(preset.mode == mode, mode.to_string())
+                            .clicked()
+                        {
+                            preset.set_mode(mode);
+                            changed = true;
+                        }
+                    }
+                });
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut preset.preamp_db, -MAX_GAIN_DB..=0.0)
+                            .text("Preamp dB"),
+                    )
+                    .changed();
+                changed |= match preset.mode {
+                    EqMode::Graphic => edit_graphic_bands(ui, &mut preset.bands),
+                    EqMode::Parametric => edit_parametric_bands(ui, &mut preset.bands),
+                };
+            }
+
+            ui.separator();
+
+            ui.heading("Genres");
+            ui.label("Tracks of these genres use their own preset.");
+            changed |= edit_genre_presets(ui, settings, &mut app.new_eq_genre);
+        });
+
+    app.show_equalizer = open;
+    if changed {
+        app.eq_settings.remove_dangling_assignments();
+        app.send_audio(AudioCommand::SetEqualizer(app.eq_settings.clone()));
+    }
+}
+
+// Picks the preset to play and edit, and saves or deletes presets.
+fn edit_presets(ui: &mut egui::Ui, settings: &mut EqSettings, new_name: &mut String) -> bool {
+    let mut changed = false;
+
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_label("Preset")
+            .selected_text(&settings.default_preset)
+            .show_ui(ui, |ui| {
+                for preset in &settings.presets {
+                    changed |= ui
+                        .selectable_value(
+                            &mut settings.default_preset,
+                            preset.name.clone(),
+                            &preset.name,
+                        )
+                        .changed();
+                }
+            });
+
+        let can_delete = settings.presets.len() > 1;
+        if ui
+            .add_enabled(can_delete, egui::Button::new("Delete"))
+            .clicked()
+        {
+            let name = settings.default_preset.clone();
+            settings.presets.retain(|preset| preset.name != name);
+            settings.default_preset = settings.presets[0].name.clone();
+            changed = true;
+        }
+    });
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(new_name);
+        let can_save = !new_name.is_empty() && settings.preset(new_name).is_none();
+        if ui
+            .add_enabled(can_save, egui::Button::new("Save As New Preset"))
+            .clicked()
+        {
+            if let Some(preset) = settings.preset(&settings.default_preset) {
+                let mut preset = preset.clone();
+                preset.name = std::mem::take(new_name);
+                settings.default_preset = preset.name.clone();
+                settings.presets.push(preset);
+                changed = true;
+            }
+        }
+    });
+
+    changed
+}
+
+fn edit_graphic_bands(ui: &mut egui::Ui, bands: &mut [Band]) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        for band in bands {
+            ui.vertical(|ui| {
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut band.gain_db, -MAX_GAIN_DB..=MAX_GAIN_DB)
+                            .vertical()
+                            .show_value(false),
+                    )
+                    .on_hover_text(format!("{:+.1} dB", band.gain_db))
+                    .changed();
+                ui.label(format_frequency(band.frequency));
+            });
+        }
+    });
+    changed
+}
+
+fn edit_parametric_bands(ui: &mut egui::Ui, bands: &mut Vec<Band>) -> bool {
+    let mut changed = false;
+    let mut to_remove = None;
+
+    egui::Grid::new("parametric_bands").show(ui, |ui| {
+        ui.label("Frequency");
+        ui.label("Gain");
+        ui.label("Q");
+        ui.end_row();
+
+        for (index, band) in bands.iter_mut().enumerate() {
+            // Steps of about a percent, so every octave takes as long to drag through
+            let speed = band.frequency * 0.01;
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut band.frequency)
+                        .clamp_range(20.0..=20000.0)
+                        .speed(speed)
+                        .suffix(" Hz"),
+                )
+                .changed();
+            changed |= ui
+                .add(egui::Slider::new(&mut band.gain_db, -MAX_GAIN_DB..=MAX_GAIN_DB).suffix(" dB"))
+                .changed();
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut band.q)
+                        .clamp_range(0.1..=10.0)
+                        .speed(0.01),
+                )
+                .changed();
+            if ui.button("Remove").clicked() {
+                to_remove = Some(index);
+            }
+            ui.end_row();
+        }
+    });
+
+    if let Some(index) = to_remove {
+        bands.remove(index);
+        changed = true;
+    }
+    if ui.button("Add Band").clicked() {
+        bands.push(Band {
+            frequency: 1000.0,
+            gain_db: 0.0,
+            q: 1.0,
+        });
+        changed = true;
+    }
+    changed
+}
+
+fn edit_genre_presets(
+    ui: &mut egui::Ui,
+    settings: &mut EqSettings,
+    new_genre: &mut String,
+) -> bool {
+    let mut changed = false;
+    let mut to_remove = None;
+    let names: Vec<String> = settings.presets.iter().map(|p| p.name.clone()).collect();
+
+    for (genre, preset) in settings.genre_presets.iter_mut() {
+        ui.horizontal(|ui| {
+            if ui.button("Remove").clicked() {
+                to_remove = Some(genre.clone());
+            }
+            egui::ComboBox::from_id_source(genre)
+                .selected_text(preset.as_str())
+                .show_ui(ui, |ui| {
+                    for name in &names {
+                        changed |= ui.selectable_value(preset, name.clone(), name).changed();
+                    }
+                });
+            ui.label(genre);
+        });
+    }
+    if let Some(genre) = to_remove {
+        settings.genre_presets.remove(&genre);
+        changed = true;
+    }
+
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(new_genre).hint_text("Genre"));
+        let can_add = !new_genre.is_empty() && !settings.genre_presets.contains_key(new_genre);
+        if ui.add_enabled(can_add, egui::Button::new("Add")).clicked() {
+            settings
+                .genre_presets
+                .insert(std::mem::take(new_genre), settings.default_preset.clone());
+            changed = true;
+        }
+    });
+
+    changed
+}
+
+fn format_frequency(frequency: f32) -> String {
+    if frequency >= 1000.0 {
+        format!("{}k", frequency / 1000.0)
+    } else {
+        format!("{}", frequency)
+    }
+}
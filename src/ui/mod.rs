@@ -1,5 +1,10 @@
 pub mod bottom_panel;
 pub mod central_panel;
+pub mod equalizer_window;
+pub mod now_playing;
+pub mod problems;
+pub mod queue_view;
+pub mod settings_window;
 pub mod side_panel;
 pub mod top_panel;
 pub mod ui_state;
@@ -0,0 +1,113 @@
+use crate::visualizer::Visualizer;
+use crate::TemplateApp;
+use egui::{pos2, vec2, Color32, Rect, Shape, Stroke};
+
+const SPECTRUM_HEIGHT: f32 = 200.0;
+const METER_HEIGHT: f32 = 12.0;
+const SCOPE_HEIGHT: f32 = 120.0;
+// The gap between the spectrum's bars, in points.
+const BAR_GAP: f32 = 2.0;
+
+/// The current track with a spectrum analyzer, level meters and an
+/// oscilloscope of what is playing.
+pub fn show_now_playing(ui: &mut egui::Ui, app: &mut TemplateApp) {
+    ui.heading("Now Playing");
+    match &app.current_track {
+        Some(track) => {
+            ui.label(&track.title);
+            if let Some(artist) = &track.artist {
+                ui.weak(artist);
+            }
+        }
+        None => {
+            ui.weak("Nothing is playing");
+        }
+    }
+
+    ui.separator();
+
+    app.visualizer.update(ui.input(|i| i.stable_dt));
+    let visualizer = &app.visualizer;
+    let color = ui.visuals().selection.bg_fill;
+    let background = ui.visuals().extreme_bg_color;
+
+    show_spectrum(ui, visualizer, color, background);
+    ui.add_space(8.0);
+    for (channel, name) in ["L", "R"].into_iter().enumerate() {
+        ui.horizontal(|ui| {
+            ui.label(name);
+            show_meter(ui, visualizer, channel, color, background);
+        });
+    }
+    ui.add_space(8.0);
+    show_scope(ui, visualizer, color, background);
+}
+
+fn show_spectrum(ui: &mut egui::Ui, visualizer: &Visualizer, color: Color32, background: Color32) {
+    let size = vec2(ui.available_width(), SPECTRUM_HEIGHT);
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, background);
+
+    let bands = visualizer.bands();
+    let width = rect.width() / bands.len() as f32;
+    for (i, height) in bands.iter().enumerate() {
+        let left = rect.left() + i as f32 * width;
+        let bar = Rect::from_min_max(
+            pos2(left, rect.bottom() - height * rect.height()),
+            pos2(left + (width - BAR_GAP).max(1.0), rect.bottom()),
+        );
+        painter.rect_filled(bar, 0.0, color);
+    }
+}
+
+// A bar as long as the channel's level, with a tick at its recent peak.
+fn show_meter(
+    ui: &mut egui::Ui,
+    visualizer: &Visualizer,
+    channel: usize,
+    color: Color32,
+    background: Color32,
+) {
+    let size = vec2(ui.available_width(), METER_HEIGHT);
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, background);
+
+    let level = visualizer.levels()[channel];
+    let bar = Rect::from_min_size(rect.min, vec2(level * rect.width(), rect.height()));
+    painter.rect_filled(bar, 0.0, color);
+
+    // Red once the peak reaches full scale
+    let peak = visualizer.peaks()[channel];
+    let peak_color = if peak >= 1.0 {
+        Color32::RED
+    } else {
+        ui.visuals().strong_text_color()
+    };
+    let x = rect.left() + peak * (rect.width() - 1.0);
+    painter.vline(x, rect.y_range(), Stroke::new(2.0, peak_color));
+}
+
+fn show_scope(ui: &mut egui::Ui, visualizer: &Visualizer, color: Color32, background: Color32) {
+    let size = vec2(ui.available_width(), SCOPE_HEIGHT);
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, background);
+
+    let samples: Vec<f32> = visualizer.scope().collect();
+    if samples.len() < 2 {
+        painter.hline(rect.x_range(), rect.center().y, Stroke::new(1.0, color));
+        return;
+    }
+    let step = rect.width() / (samples.len() - 1) as f32;
+    let points = samples
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            let y = rect.center().y - sample.clamp(-1.0, 1.0) * rect.height() / 2.0;
+            pos2(rect.left() + i as f32 * step, y)
+        })
+        .collect();
+    painter.add(Shape::line(points, Stroke::new(1.5, color)));
+}
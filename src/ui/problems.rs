@@ -0,0 +1,179 @@
+use crate::audio_thread::{AudioCommand, AudioError, AudioState};
+use crate::audio_track::Track;
+use crate::TemplateApp;
+use serde::{Deserialize, Serialize};
+
+// How long a toast stays up, in seconds.
+const TOAST_SECONDS: f32 = 6.0;
+// Older toasts make way once there are more than this many.
+const MAX_TOASTS: usize = 4;
+
+/// A short-lived message in the corner of the window.
+pub struct Toast {
+    pub message: String,
+    // Seconds since it was shown, or last repeated.
+    age: f32,
+}
+
+/// A track that failed to play, kept until it is dismissed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Problem {
+    pub track: Track,
+    pub error: String,
+}
+
+/// Tells the user about an error from the audio thread. Tracks that fail are
+/// listed under problems, and an unplayable track is passed over in the queue
+/// until it is retried, so the one after it is lined up instead. One that
+/// isn't in the library is taken off the queue if it is about to play next.
+/// A loop that couldn't be set is cleared.
+pub fn report(app: &mut TemplateApp, error: AudioError) {
+    let message = error.to_string();
+    if let Some(track) = error.track() {
+        // Skipped packets are the least of a track's problems, they don't
+        // replace one already listed
+        let listed = app
+            .problems
+            .iter()
+            .any(|problem| problem.track.is_same(track));
+        if !(listed && matches!(error, AudioError::PacketsDropped { .. })) {
+            add_problem(app, track.clone(), message.clone());
+        }
+    }
+    match &error {
+        AudioError::Unplayable { track, .. } => match track.id {
+            Some(id) => app.queue.mark_failed(id),
+            None if app.queue.peek().is_some_and(|next| next.is_same(track)) => {
+                // Failed tracks ahead of it stay where they are
+                let tracks = app.queue.get_tracks();
+                if let Some(position) = tracks.iter().position(|t| !app.queue.has_failed(t)) {
+                    app.queue.remove(position);
+                }
+                app.queue_selection.clear();
+            }
+            None => (),
+        },
+        // The track plays on without looping, so the A-B buttons say so
+        AudioError::Loop { .. } => {
+            app.loop_start = None;
+            app.loop_end = None;
+        }
+        _ => (),
+    }
+
+    show_toast(app, message);
+}
+
+/// Tells the user about something wrong with a track outside of playback,
+/// such as the library failing to measure it.
+pub fn report_track(app: &mut TemplateApp, track: Track, message: String) {
+    add_problem(app, track, message.clone());
+    show_toast(app, message);
+}
+
+/// Tells the user about something wrong that isn't about any one track.
+pub fn report_message(app: &mut TemplateApp, message: String) {
+    show_toast(app, message);
+}
+
+fn add_problem(app: &mut TemplateApp, track: Track, error: String) {
+    app.problems
+        .retain(|problem| !problem.track.is_same(&track));
+    app.problems.push(Problem { track, error });
+}
+
+fn show_toast(app: &mut TemplateApp, message: String) {
+    // The same error again, such as a device that keeps failing to open,
+    // keeps its toast up rather than stacking another
+    match app.toasts.iter_mut().find(|toast| toast.message == message) {
+        Some(toast) => toast.age = 0.0,
+        None => app.toasts.push(Toast { message, age: 0.0 }),
+    }
+    if app.toasts.len() > MAX_TOASTS {
+        app.toasts.remove(0);
+    }
+}
+
+/// The toasts, newest at the bottom, in the top right corner.
+pub fn show_toasts(ctx: &egui::Context, app: &mut TemplateApp) {
+    let seconds = ctx.input(|i| i.stable_dt);
+    for toast in &mut app.toasts {
+        toast.age += seconds;
+    }
+    app.toasts.retain(|toast| toast.age < TOAST_SECONDS);
+    if app.toasts.is_empty() {
+        return;
+    }
+
+    let mut dismissed = None;
+    egui::Area::new("toasts")
+        .anchor(egui::Align2::RIGHT_TOP, [-8.0, 32.0])
+        .show(ctx, |ui| {
+            for (i, toast) in app.toasts.iter().enumerate() {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(ui.visuals().warn_fg_color, "⚠");
+                        ui.label(&toast.message);
+                        if ui.small_button("✕").clicked() {
+                            dismissed = Some(i);
+                        }
+                    });
+                });
+            }
+        });
+    if let Some(i) = dismissed {
+        app.toasts.remove(i);
+    }
+}
+
+/// Every track that failed to play, with its error, until dismissed.
+pub fn show_problems_window(ctx: &egui::Context, app: &mut TemplateApp) {
+    let mut open = app.show_problems;
+    let mut to_retry = None;
+    let mut to_dismiss = None;
+
+    egui::Window::new("Problems")
+        .open(&mut open)
+        .resizable(true)
+        .show(ctx, |ui| {
+            if app.problems.is_empty() {
+                ui.label("No problems.");
+                return;
+            }
+            if ui.button("Clear").clicked() {
+                app.problems.clear();
+                return;
+            }
+            ui.separator();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (i, problem) in app.problems.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(&problem.track.title)
+                            .on_hover_text(&problem.track.file_path);
+                        if ui.small_button("Retry").clicked() {
+                            to_retry = Some(i);
+                        }
+                        if ui.small_button("✕").clicked() {
+                            to_dismiss = Some(i);
+                        }
+                    });
+                    ui.colored_label(ui.visuals().error_fg_color, &problem.error);
+                    ui.separator();
+                }
+            });
+        });
+
+    app.show_problems = open;
+    if let Some(i) = to_dismiss {
+        app.problems.remove(i);
+    }
+    // Tried again, it is listed again if it still fails
+    if let Some(i) = to_retry {
+        let problem = app.problems.remove(i);
+        if let Some(id) = problem.track.id {
+            app.queue.retry(id);
+        }
+        app.audio_state = AudioState::Playing;
+        app.send_audio(AudioCommand::PlaySong(problem.track));
+    }
+}
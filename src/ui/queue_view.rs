@@ -0,0 +1,144 @@
+use crate::audio_thread::{AudioCommand, AudioState};
+use crate::audio_track;
+use crate::TemplateApp;
+
+/// The queue in play order. Rows are dragged by their handle to reorder
+/// them, and ticked to remove several at once.
+pub fn show_queue_view(ui: &mut egui::Ui, app: &mut TemplateApp) {
+    let tracks = app.queue.get_tracks();
+    // Positions ticked or dragged before the queue changed would point at
+    // the wrong tracks, or past the end
+    if app.queue_selection_revision != app.queue.revision() {
+        app.queue_selection.clear();
+        app.queue_selection_revision = app.queue.revision();
+    }
+    if app
+        .queue_drag
+        .is_some_and(|(_, revision)| revision != app.queue.revision())
+    {
+        app.queue_drag = None;
+    }
+
+    ui.heading("Queue");
+    ui.label(format!(
+        "{} tracks, {}",
+        tracks.len(),
+        audio_track::format_duration(audio_track::total_duration(&tracks))
+    ));
+    ui.horizontal(|ui| {
+        let can_remove = !app.queue_selection.is_empty();
+        if ui
+            .add_enabled(can_remove, egui::Button::new("Remove Selected"))
+            .clicked()
+        {
+            // From the back, so the positions still to remove stay put
+            for &position in app.queue_selection.iter().rev() {
+                app.queue.remove(position);
+            }
+            app.queue_selection.clear();
+        }
+        if ui.button("Clear").clicked() {
+            app.queue.clear();
+            app.queue_selection.clear();
+        }
+    });
+
+    ui.separator();
+
+    let mut to_play = None;
+    let mut to_remove = None;
+    let mut to_move = None;
+
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        let mut rows = Vec::with_capacity(tracks.len());
+        for (position, track) in tracks.iter().enumerate() {
+            let row = ui.horizontal(|ui| {
+                let handle = ui
+                    .add(egui::Label::new("☰").sense(egui::Sense::drag()))
+                    .on_hover_cursor(egui::CursorIcon::Grab);
+                if handle.drag_started() {
+                    app.queue_drag = Some((position, app.queue.revision()));
+                }
+
+                let mut selected = app.queue_selection.contains(&position);
+                if ui.checkbox(&mut selected, "").changed() {
+                    if selected {
+                        app.queue_selection.insert(position);
+                    } else {
+                        app.queue_selection.remove(&position);
+                    }
+                }
+
+                let button = egui::Button::new(&track.title).fill(ui.style().visuals.window_fill());
+                let response = ui.add_enabled(!track.missing, button);
+                if response.clicked() {
+                    to_play = Some(position);
+                }
+                response.context_menu(|ui| {
+                    if ui.button("Play Next").clicked() {
+                        to_move = Some((position, 0));
+                        ui.close_menu();
+                    }
+                    if ui.button("Remove from Queue").clicked() {
+                        to_remove = Some(position);
+                        ui.close_menu();
+                    }
+                });
+
+                if let Some(duration) = track.duration {
+                    ui.label(audio_track::format_duration(duration));
+                }
+                if track.missing {
+                    ui.colored_label(egui::Color32::RED, "File missing");
+                }
+                if app.queue.has_failed(track) {
+                    ui.colored_label(egui::Color32::RED, "Failed, skipped until retried");
+                }
+                if let Some(artist) = &track.artist {
+                    ui.weak(artist);
+                }
+            });
+            rows.push(row.response.rect);
+        }
+
+        // While a row is dragged, a line shows where it will land
+        let Some((from, _)) = app.queue_drag else {
+            return;
+        };
+        let pointer = ui.input(|i| i.pointer.interact_pos());
+        let target = pointer.map_or(from, |pointer| {
+            rows.iter()
+                .position(|rect| pointer.y < rect.center().y)
+                .unwrap_or(rows.len())
+        });
+        let line_y = match rows.get(target) {
+            Some(rect) => rect.top(),
+            None => rows.last().map_or(0.0, |rect| rect.bottom()),
+        };
+        ui.painter().hline(
+            ui.max_rect().x_range(),
+            line_y,
+            ui.visuals().selection.stroke,
+        );
+        if ui.input(|i| i.pointer.any_released()) {
+            // Taking the row out first shifts the rows after it up by one
+            let to = if target > from { target - 1 } else { target };
+            to_move = Some((from, to));
+            app.queue_drag = None;
+        }
+    });
+
+    if let Some((from, to)) = to_move {
+        app.queue.move_track(from, to);
+        app.queue_selection.clear();
+    }
+    if let Some(position) = to_remove {
+        app.queue.remove(position);
+        app.queue_selection.clear();
+    }
+    if let Some(track) = to_play.and_then(|position| app.queue.remove(position)) {
+        app.queue_selection.clear();
+        app.audio_state = AudioState::Playing;
+        app.send_audio(AudioCommand::PlaySong(track));
+    }
+}
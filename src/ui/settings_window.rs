@@ -0,0 +1,266 @@
+use crate::audio_thread::AudioCommand;
+use crate::audio_track::ResumeSettings;
+use crate::crossfade::{CrossfadeSettings, FadeCurve};
+use crate::library_thread::LibraryCommand;
+use crate::loudness::{NormalizationMode, NormalizationSettings};
+use crate::TemplateApp;
+use std::path::Path;
+use std::time::Duration;
+
+pub fn show_settings_window(ctx: &egui::Context, app: &mut TemplateApp) {
+    let mut open = app.show_settings;
+    let mut changed = false;
+    let mut crossfade_changed = false;
+    let mut normalization_changed = false;
+    let mut speed_changed = false;
+    let mut resume_changed = false;
+    let mut device_changed = false;
+
+    egui::Window::new("Settings")
+        .open(&mut open)
+        .resizable(true)
+        .show(ctx, |ui| {
+            let settings = &mut app.library_settings;
+
+            ui.heading("Music Folders");
+            changed |= edit_list(ui, &mut settings.roots, &mut app.new_library_root, |root| {
+                if Path::new(root).is_dir() {
+                    Ok(())
+                } else {
+                    Err("Folder does not exist.".to_string())
+                }
+            });
+
+            ui.separator();
+
+            ui.heading("Include Patterns");
+            ui.label("Only files matching one of these are added. Leave empty to add everything.");
+            changed |= edit_list(
+                ui,
+                &mut settings.include,
+                &mut app.new_include_pattern,
+                validate_pattern,
+            );
+
+            ui.separator();
+
+            ui.heading("Exclude Patterns");
+            ui.label("Files and folders matching any of these are skipped.");
+            changed |= edit_list(
+                ui,
+                &mut settings.exclude,
+                &mut app.new_exclude_pattern,
+                validate_pattern,
+            );
+
+            ui.separator();
+
+            ui.heading("Output Device");
+            device_changed = edit_output_device(ui, app);
+
+            ui.separator();
+
+            ui.heading("Playback");
+            crossfade_changed = edit_crossfade(ui, &mut app.crossfade);
+            ui.add_space(8.0);
+            normalization_changed = edit_normalization(ui, &mut app.normalization);
+            ui.add_space(8.0);
+            speed_changed = ui
+                .checkbox(&mut app.speed.per_track, "Remember the speed of each track")
+                .on_hover_text("For podcasts and audiobooks that each want their own speed")
+                .changed();
+            ui.add_space(8.0);
+            resume_changed = edit_resume(ui, &mut app.resume);
+        });
+
+    app.show_settings = open;
+    if changed {
+        app.send_library(LibraryCommand::Rescan(app.library_settings.clone()));
+    }
+    if crossfade_changed {
+        app.send_audio(AudioCommand::SetCrossfade(app.crossfade.clone()));
+    }
+    if device_changed {
+        app.send_audio(AudioCommand::SetOutputDevice(app.output_device.clone()));
+    }
+    if normalization_changed {
+        app.send_audio(AudioCommand::SetNormalization(app.normalization.clone()));
+    }
+    if speed_changed {
+        app.send_audio(AudioCommand::SetSpeed(app.speed.clone()));
+    }
+    if resume_changed {
+        app.send_audio(AudioCommand::SetResume(app.resume.clone()));
+    }
+}
+
+// Returns whether a different device was picked.
+fn edit_output_device(ui: &mut egui::Ui, app: &mut TemplateApp) -> bool {
+    let mut changed = false;
+    let selected = match &app.output_device {
+        Some(name) if !app.output_devices.contains(name) => format!("{} (unplugged)", name),
+        Some(name) => name.clone(),
+        None => "System default".to_string(),
+    };
+
+    egui::ComboBox::from_label("Device")
+        .selected_text(selected)
+        .show_ui(ui, |ui| {
+            changed |= ui
+                .selectable_value(&mut app.output_device, None, "System default")
+                .changed();
+            for name in &app.output_devices {
+                changed |= ui
+                    .selectable_value(&mut app.output_device, Some(name.clone()), name)
+                    .changed();
+            }
+        });
+
+    if let Some(name) = &app.active_output_device {
+        ui.label(format!("Playing through {}", name));
+    } else if app.no_output_device {
+        ui.colored_label(egui::Color32::RED, "No output device");
+    }
+
+    changed
+}
+
+// Returns whether any of the settings changed.
+fn edit_crossfade(ui: &mut egui::Ui, crossfade: &mut CrossfadeSettings) -> bool {
+    let mut changed = ui
+        .checkbox(&mut crossfade.enabled, "Crossfade between tracks")
+        .changed();
+
+    ui.add_enabled_ui(crossfade.enabled, |ui| {
+        let mut seconds = crossfade.duration.as_secs_f32();
+        if ui
+            .add(egui::Slider::new(&mut seconds, 0.5..=12.0).text("Seconds"))
+            .changed()
+        {
+            crossfade.duration = Duration::from_secs_f32(seconds);
+            changed = true;
+        }
+
+        egui::ComboBox::from_label("Curve")
+            .selected_text(crossfade.curve.to_string())
+            .show_ui(ui, |ui| {
+                for curve in FadeCurve::ALL {
+                    changed |= ui
+                        .selectable_value(&mut crossfade.curve, curve, curve.to_string())
+                        .changed();
+                }
+            });
+
+        changed |= ui
+            .checkbox(
+                &mut crossfade.skip_same_album,
+                "Play tracks from the same album without crossfading",
+            )
+            .changed();
+    });
+
+    changed
+}
+
+// Returns whether any of the settings changed.
+fn edit_resume(ui: &mut egui::Ui, resume: &mut ResumeSettings) -> bool {
+    let mut changed = ui
+        .checkbox(
+            &mut resume.enabled,
+            "Resume long tracks where they were left",
+        )
+        .on_hover_text("For audiobooks, podcasts and mixes")
+        .changed();
+
+    ui.add_enabled_ui(resume.enabled, |ui| {
+        let mut minutes = resume.min_duration.as_secs() / 60;
+        if ui
+            .add(egui::Slider::new(&mut minutes, 1..=120).text("Minutes or longer"))
+            .changed()
+        {
+            resume.min_duration = Duration::from_secs(minutes * 60);
+            changed = true;
+        }
+    });
+
+    changed
+}
+
+// Returns whether any of the settings changed.
+fn edit_normalization(ui: &mut egui::Ui, normalization: &mut NormalizationSettings) -> bool {
+    let mut changed = false;
+
+    egui::ComboBox::from_label("Volume normalization")
+        .selected_text(normalization.mode.to_string())
+        .show_ui(ui, |ui| {
+            for mode in NormalizationMode::ALL {
+                changed |= ui
+                    .selectable_value(&mut normalization.mode, mode, mode.to_string())
+                    .changed();
+            }
+        });
+
+    ui.add_enabled_ui(normalization.mode != NormalizationMode::Off, |ui| {
+        changed |= ui
+            .add(egui::Slider::new(&mut normalization.preamp_db, -6.0..=12.0).text("Preamp dB"))
+            .changed();
+        changed |= ui
+            .checkbox(
+                &mut normalization.prevent_clipping,
+                "Lower the gain of tracks that would clip",
+            )
+            .changed();
+    });
+
+    changed
+}
+
+// Shows the entries of `items` with a remove button each, and an input to add
+// a new one. Returns whether `items` changed.
+fn edit_list(
+    ui: &mut egui::Ui,
+    items: &mut Vec<String>,
+    new_item: &mut String,
+    validate: impl Fn(&str) -> Result<(), String>,
+) -> bool {
+    let mut changed = false;
+
+    let mut to_remove = None;
+    for (index, item) in items.iter().enumerate() {
+        ui.horizontal(|ui| {
+            if ui.button("Remove").clicked() {
+                to_remove = Some(index);
+            }
+            ui.label(item);
+        });
+    }
+    if let Some(index) = to_remove {
+        items.remove(index);
+        changed = true;
+    }
+
+    let validation = validate(new_item);
+    ui.horizontal(|ui| {
+        let response = ui.text_edit_singleline(new_item);
+        let enter_pressed = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        let can_add = !new_item.is_empty() && validation.is_ok() && !items.contains(new_item);
+        if ui.add_enabled(can_add, egui::Button::new("Add")).clicked() || (enter_pressed && can_add)
+        {
+            items.push(std::mem::take(new_item));
+            changed = true;
+        }
+    });
+    if let Err(error) = validation {
+        if !new_item.is_empty() {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+    }
+
+    changed
+}
+
+fn validate_pattern(pattern: &str) -> Result<(), String> {
+    glob::Pattern::new(pattern)
+        .map(|_| ())
+        .map_err(|e| format!("Invalid pattern: {}", e))
+}
@@ -1,5 +1,5 @@
+use crate::audio_thread::{AudioCommand, AudioState};
 use crate::audio_track;
-use crate::queue::Queue;
 use crate::playlist::Playlist;
 use crate::TemplateApp;
 
@@ -9,8 +9,10 @@ pub fn show_side_panel(ctx: &egui::Context, app: &mut TemplateApp) {
         ui.separator();
 
         if ui.button("All Songs").clicked() {
-            app.track_list = audio_track::get_tracks();
+            app.track_list = app.library_tracks.clone();
             app.current_playlist = None;
+            app.show_queue = false;
+            app.show_now_playing = false;
         }
 
         ui.separator();
@@ -21,9 +23,31 @@ pub fn show_side_panel(ctx: &egui::Context, app: &mut TemplateApp) {
 
         ui.separator();
 
-        if ui.button("Queue").clicked() {
-            app.track_list = Queue::get_tracks(&app.queue);
-            app.current_playlist = Some("Queue".to_string());
+        let queue_label = format!(
+            "Queue ({})",
+            audio_track::format_duration(app.queue.total_duration())
+        );
+        if ui.button(queue_label).clicked() {
+            app.show_queue = true;
+            app.show_now_playing = false;
+        }
+
+        if ui.button("Now Playing").clicked() {
+            app.show_now_playing = true;
+            app.show_queue = false;
+        }
+
+        let mut to_play = None;
+        ui.collapsing(format!("History ({})", app.queue.history.len()), |ui| {
+            for track in app.queue.history.iter().rev() {
+                if ui.button(&track.title).clicked() {
+                    to_play = Some(track.clone());
+                }
+            }
+        });
+        if let Some(track) = to_play {
+            app.audio_state = AudioState::Playing;
+            app.send_audio(AudioCommand::PlaySong(track));
         }
 
         if app.show_playlist_input {
@@ -68,18 +92,30 @@ pub fn show_side_panel(ctx: &egui::Context, app: &mut TemplateApp) {
         let mut playlists_to_delete: Vec<String> = Vec::new();
 
         for playlist in &app.playlist_list {
-            let button = ui.button(&playlist.name);
+            let button = ui.button(&playlist.name).on_hover_text(format!(
+                "{} tracks, {}",
+                playlist.entries.len(),
+                audio_track::format_duration(playlist.total_duration())
+            ));
 
             if button.clicked() {
-                app.track_list = playlist.tracks.clone();
+                app.track_list = playlist.tracks();
                 app.current_playlist = Some(playlist.name.clone());
+                app.show_queue = false;
+                app.show_now_playing = false;
             }
 
             button.context_menu(|ui| {
+                if ui.button("Add to Queue").clicked() {
+                    app.queue.add_tracks(playlist.tracks());
+                    ui.close_menu();
+                }
                 if ui.button("Delete Playlist").clicked() {
                     playlists_to_delete.push(playlist.name.clone());
-                    app.track_list = audio_track::get_tracks();
+                    app.track_list = app.library_tracks.clone();
                     app.current_playlist = None;
+                    app.show_queue = false;
+                    app.show_now_playing = false;
                 }
             });
         }
@@ -1,17 +1,37 @@
+use crate::library_thread::LibraryCommand;
 use crate::TemplateApp;
 
-pub fn show_top_panel(ctx: &egui::Context, _frame: &mut eframe::Frame, _app: &mut TemplateApp) {
+pub fn show_top_panel(ctx: &egui::Context, _frame: &mut eframe::Frame, app: &mut TemplateApp) {
     egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
         // The top panel is often a good place for a menu bar:
 
         egui::menu::bar(ui, |ui| {
-            #[cfg(not(target_arch = "wasm32"))] // no File->Quit on web pages!
-            {
-                ui.menu_button("File", |ui| {
-                    if ui.button("Quit").clicked() {
-                        _frame.close();
-                    }
-                });
+            ui.menu_button("File", |ui| {
+                if ui.button("Rescan Library").clicked() {
+                    app.send_library(LibraryCommand::Rescan(app.library_settings.clone()));
+                    ui.close_menu();
+                }
+                if ui.button("Quit").clicked() {
+                    _frame.close();
+                }
+            });
+            ui.add_space(16.0);
+
+            if ui.button("Settings").clicked() {
+                app.show_settings = !app.show_settings;
+            }
+            ui.add_space(16.0);
+
+            if ui.button("Equalizer").clicked() {
+                app.show_equalizer = !app.show_equalizer;
+            }
+            ui.add_space(16.0);
+
+            if !app.problems.is_empty() {
+                let label = format!("⚠ Problems ({})", app.problems.len());
+                if ui.button(label).clicked() {
+                    app.show_problems = !app.show_problems;
+                }
                 ui.add_space(16.0);
             }
 
@@ -1,35 +1,212 @@
 use crate::audio_thread::AudioCommand;
+use crate::audio_thread::AudioEvent;
 use crate::audio_thread::AudioState;
+use crate::audio_thread::TrackStart;
+use crate::audio_track::{Track, TrackId};
+use crate::library;
+use crate::library_thread::LibraryCommand;
+use crate::library_thread::LibraryEvent;
+use crate::queue::RepeatMode;
+use crate::ui::problems;
 use crate::TemplateApp;
+use std::time::Duration;
 
 pub fn update_app_state(app: &mut TemplateApp) {
-    let (state_sender, state_receiver) = std::sync::mpsc::channel();
-    app.audio_thread_sender
-        .send(AudioCommand::GetState(state_sender))
-        .unwrap();
-
-    // Receive the current state
-    match state_receiver.recv() {
-        Ok(AudioState::Playing) => {
-            app.audio_state = AudioState::Playing;
-        }
-        Ok(AudioState::Paused) => {
-            app.audio_state = AudioState::Paused;
+    // Pick up library changes published by the library thread
+    while let Ok(event) = app.library_receiver.try_recv() {
+        match event {
+            LibraryEvent::TracksChanged(tracks) => {
+                app.library_tracks = tracks;
+                if app.current_playlist.is_none() {
+                    app.track_list = app.library_tracks.clone();
+                }
+                if app.library_scanned {
+                    relink_library_tracks(app);
+                }
+            }
+            LibraryEvent::ResumePositionSaved { track, position } => {
+                set_resume_position(app, track, position);
+            }
+            LibraryEvent::ScanFinished => {
+                if !app.library_scanned {
+                    app.library_scanned = true;
+                    relink_library_tracks(app);
+                    if app.eq_settings.move_path_presets(&app.library_tracks) {
+                        app.send_audio(AudioCommand::SetEqualizer(app.eq_settings.clone()));
+                    }
+                }
+            }
+            LibraryEvent::AnalysisFailed { track, error } => {
+                let found = app.library_tracks.iter().find(|t| t.id == Some(track));
+                if let Some(track) = found.cloned() {
+                    let message = format!("Could not measure {}: {}", track.title, error);
+                    problems::report_track(app, track, message);
+                }
+            }
+            LibraryEvent::Waveform(id, waveform) => {
+                let current = app.current_track.as_ref().and_then(|t| t.id);
+                if current == Some(id) {
+                    app.waveform = Some((id, waveform));
+                }
+            }
         }
-        Ok(AudioState::Empty) => {
-            if app.queue.tracks.len() > 0 {
-                app.audio_state = AudioState::Playing;
-                app.audio_thread_sender
-                    .send(AudioCommand::PlaySong(
-                        app.queue.tracks[0].file_path.clone(),
-                    ))
-                    .unwrap();
-                app.queue.tracks.remove(0);
-            } else {
-                app.audio_state = AudioState::Empty;
-                app.current_track = None;
+    }
+
+    // Pick up playback changes published by the audio thread
+    while let Ok(event) = app.audio_receiver.try_recv() {
+        match event {
+            AudioEvent::StateChanged(state) => {
+                app.audio_state = state;
+                if state != AudioState::Playing {
+                    save_resume_position(app);
+                }
+                if state == AudioState::Empty {
+                    if let Some(track) = app.current_track.take() {
+                        app.queue.push_history(track);
+                    }
+                    app.track_progress = 0.0;
+                }
+            }
+            AudioEvent::TrackStarted {
+                track,
+                duration,
+                start,
+            } => {
+                // Before the track that was playing is let go of
+                save_resume_position(app);
+                match start {
+                    // The next track was already decoded and has taken over. On
+                    // repeat one that is the current track again, which isn't
+                    // taken from the queue
+                    TrackStart::Next => {
+                        let repeated = app.queue.repeat == RepeatMode::One
+                            && app
+                                .current_track
+                                .as_ref()
+                                .is_some_and(|t| t.is_same(&track));
+                        if !repeated && app.queue.peek().is_some_and(|t| t.is_same(&track)) {
+                            app.queue.pop_next();
+                            app.queue_selection.clear();
+                        }
+                        app.next_track = None;
+                    }
+                    // Going back through the history, the track that was
+                    // playing is up next again
+                    TrackStart::Previous => {
+                        if app.queue.history.last().is_some_and(|t| t.is_same(&track)) {
+                            app.queue.history.pop();
+                        }
+                        if let Some(current) = app.current_track.take() {
+                            app.queue.insert(0, current);
+                            app.queue_selection.clear();
+                        }
+                    }
+                    TrackStart::Played => (),
+                }
+                if let Some(previous) = app.current_track.replace(*track) {
+                    app.queue.push_history(previous);
+                }
+                // Keep the waveform when a track follows itself
+                let id = app.current_track.as_ref().and_then(|t| t.id);
+                if app.waveform.as_ref().map(|(held, _)| *held) != id {
+                    app.waveform = None;
+                    if let Some(id) = id {
+                        app.send_library(LibraryCommand::LoadWaveform(id));
+                    }
+                }
+                app.track_duration = duration.unwrap_or_default().as_secs_f32();
+                app.track_progress = 0.0;
+                // The loop belonged to the track before
+                app.loop_start = None;
+                app.loop_end = None;
+            }
+            AudioEvent::Position(position) => {
+                app.track_progress = position.as_secs_f32();
+            }
+            AudioEvent::TrackEnded => (),
+            AudioEvent::OutputDevices(devices) => app.output_devices = devices,
+            AudioEvent::OutputChanged(device) => {
+                app.no_output_device = device.is_none();
+                app.active_output_device = device;
             }
+            AudioEvent::Error(error) => problems::report(app, error),
+        }
+    }
+
+    app.queue.skip_missing();
+
+    if app.audio_state == AudioState::Empty {
+        if let Some(track) = app.queue.pop_next() {
+            app.queue_selection.clear();
+            // The audio thread reports back if the track can't be played
+            app.audio_state = AudioState::Playing;
+            app.send_audio(AudioCommand::PlaySong(track));
+        }
+    }
+
+    // Have the audio thread decode the next track ahead of time, so it
+    // follows the current track without a gap
+    let next_track = if app.queue.repeat == RepeatMode::One {
+        // A repeated track starts over rather than resuming
+        app.current_track.clone().map(|track| Track {
+            track_progress: None,
+            ..track
+        })
+    } else {
+        app.queue.peek().cloned()
+    };
+    // It is decoded again if where it resumes has changed
+    let changed = match (&next_track, &app.next_track) {
+        (Some(next), Some(sent)) => {
+            !next.is_same(sent) || next.track_progress != sent.track_progress
         }
-        _ => (),
+        (next, sent) => next.is_some() != sent.is_some(),
+    };
+    if changed {
+        app.send_audio(AudioCommand::SetNext(next_track.clone()));
+        app.next_track = next_track;
+    }
+}
+
+// Remembers where the current track was left, for tracks that resume.
+fn save_resume_position(app: &mut TemplateApp) {
+    let Some(track) = &app.current_track else {
+        return;
+    };
+    let progress = Duration::from_secs_f32(app.track_progress);
+    if let (Some(id), Some(position)) = (track.id, app.resume.position_to_save(track, progress)) {
+        app.send_library(LibraryCommand::SaveResumePosition {
+            track: id,
+            position,
+        });
+    }
+}
+
+// Points playlist and queue entries at their library tracks, marking those
+// whose file is gone.
+fn relink_library_tracks(app: &mut TemplateApp) {
+    let tracks = &app.library_tracks;
+    for playlist in &mut app.playlist_list {
+        library::relink_tracks(playlist.tracks_mut(), tracks);
+    }
+    library::relink_tracks(&mut app.queue.tracks, tracks);
+    library::relink_tracks(app.current_track.iter_mut(), tracks);
+    if app.current_playlist.is_some() {
+        library::relink_tracks(&mut app.track_list, tracks);
+    }
+}
+
+// Updates where a track resumes in every copy of it held outside the library.
+fn set_resume_position(app: &mut TemplateApp, id: TrackId, position: Option<Duration>) {
+    let playlists = app.playlist_list.iter_mut().flat_map(|p| p.tracks_mut());
+    let tracks = app
+        .library_tracks
+        .iter_mut()
+        .chain(&mut app.track_list)
+        .chain(&mut app.queue.tracks)
+        .chain(&mut app.current_track)
+        .chain(playlists);
+    for track in tracks.filter(|track| track.id == Some(id)) {
+        track.track_progress = position;
     }
 }
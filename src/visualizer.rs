@@ -0,0 +1,248 @@
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+// How many frames the tap holds for the UI, a bit over a third of a second
+// at 44.1 kHz. Frames that don't fit are dropped.
+const TAP_FRAMES: usize = 16384;
+// The number of frames the spectrum is taken over.
+const FFT_SIZE: usize = 2048;
+// The number of frames the oscilloscope shows.
+const SCOPE_FRAMES: usize = 1024;
+pub const BANDS: usize = 48;
+const MIN_FREQUENCY: f32 = 30.0;
+// The quietest level shown, anything below sits at the bottom.
+const FLOOR_DB: f32 = -72.0;
+// How far the levels and bars fall each second, as a share of the full height.
+const FALL_PER_SECOND: f32 = 1.5;
+const PEAK_FALL_PER_SECOND: f32 = 0.4;
+// The levels are measured over this much of the most recent audio.
+const LEVEL_SECONDS: f32 = 0.05;
+// After this long without audio, playback has stopped and everything falls.
+const IDLE_SECONDS: f32 = 0.1;
+
+/// The player's end of the visualizer. Pushing never blocks or allocates, so
+/// it is safe on the output's thread; when the UI falls behind, frames are
+/// dropped instead. Only the first two channels are kept, mono is doubled.
+pub struct SampleTap {
+    producer: HeapProducer<[f32; 2]>,
+    sample_rate: Arc<AtomicU32>,
+}
+
+impl SampleTap {
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        let left = frame.first().copied().unwrap_or(0.0);
+        let right = frame.get(1).copied().unwrap_or(left);
+        let _ = self.producer.push([left, right]);
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        self.sample_rate
+            .store(sample_rate.max(1), Ordering::Relaxed);
+    }
+}
+
+/// The UI's end of the visualizer. Each `update` takes in what the tap has
+/// gathered and works out the spectrum, levels and oscilloscope trace.
+pub struct Visualizer {
+    consumer: HeapConsumer<[f32; 2]>,
+    sample_rate: Arc<AtomicU32>,
+    recent: VecDeque<[f32; 2]>,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    // Bar heights from 0 to 1.
+    bands: Vec<f32>,
+    levels: [f32; 2],
+    peaks: [f32; 2],
+    idle: f32,
+}
+
+impl Visualizer {
+    pub fn new() -> (Visualizer, SampleTap) {
+        let (producer, consumer) = HeapRb::new(TAP_FRAMES).split();
+        let sample_rate = Arc::new(AtomicU32::new(44100));
+        // A Hann window keeps loud bands from smearing into the quiet ones
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        let visualizer = Visualizer {
+            consumer,
+            sample_rate: sample_rate.clone(),
+            recent: VecDeque::with_capacity(FFT_SIZE),
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            window,
+            buffer: vec![Complex::default(); FFT_SIZE],
+            bands: vec![0.0; BANDS],
+            levels: [0.0; 2],
+            peaks: [0.0; 2],
+            idle: 0.0,
+        };
+        let tap = SampleTap {
+            producer,
+            sample_rate,
+        };
+        (visualizer, tap)
+    }
+
+    /// Takes in the frames played since the last call, `seconds` ago.
+    pub fn update(&mut self, seconds: f32) {
+        let mut received = false;
+        while let Some(frame) = self.consumer.pop() {
+            if self.recent.len() == FFT_SIZE {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(frame);
+            received = true;
+        }
+        self.idle = if received { 0.0 } else { self.idle + seconds };
+        if self.idle > IDLE_SECONDS {
+            self.recent.clear();
+        }
+
+        let spectrum = self.spectrum();
+        let fall = FALL_PER_SECOND * seconds;
+        for (bar, target) in self.bands.iter_mut().zip(spectrum) {
+            *bar = target.max(*bar - fall);
+        }
+
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f32;
+        let length = ((sample_rate * LEVEL_SECONDS) as usize).clamp(1, FFT_SIZE);
+        let latest = self.recent.iter().rev().take(length);
+        let mut squares = [0.0; 2];
+        let mut highest = [0.0f32; 2];
+        for frame in latest {
+            for channel in 0..2 {
+                squares[channel] += frame[channel] * frame[channel];
+                highest[channel] = highest[channel].max(frame[channel].abs());
+            }
+        }
+        for channel in 0..2 {
+            let rms = (squares[channel] / length as f32).sqrt();
+            self.levels[channel] = height(rms).max(self.levels[channel] - fall);
+            let peak_fall = PEAK_FALL_PER_SECOND * seconds;
+            self.peaks[channel] = height(highest[channel]).max(self.peaks[channel] - peak_fall);
+        }
+    }
+
+    /// The heights of the spectrum's bars from 0 to 1, low to high, on a
+    /// logarithmic frequency scale.
+    pub fn bands(&self) -> &[f32] {
+        &self.bands
+    }
+
+    /// The loudness of the left and right channel from 0 to 1, in dB.
+    pub fn levels(&self) -> [f32; 2] {
+        self.levels
+    }
+
+    /// The highest sample of each channel lately, from 0 to 1, in dB.
+    pub fn peaks(&self) -> [f32; 2] {
+        self.peaks
+    }
+
+    /// The recent waveform mixed to mono, starting where it crosses zero on
+    /// the way up so a steady tone stands still.
+    pub fn scope(&self) -> impl Iterator<Item = f32> + '_ {
+        let mono = |frame: &[f32; 2]| (frame[0] + frame[1]) / 2.0;
+        let spare = self.recent.len().saturating_sub(SCOPE_FRAMES);
+        let start = (1..spare)
+            .find(|&i| mono(&self.recent[i - 1]) < 0.0 && mono(&self.recent[i]) >= 0.0)
+            .unwrap_or(spare);
+        self.recent.iter().skip(start).take(SCOPE_FRAMES).map(mono)
+    }
+
+    // The height of each band in the recent audio, silence when there isn't
+    // enough of it yet.
+    fn spectrum(&mut self) -> Vec<f32> {
+        if self.recent.len() < FFT_SIZE {
+            return vec![0.0; BANDS];
+        }
+        for ((value, frame), weight) in self.buffer.iter_mut().zip(&self.recent).zip(&self.window) {
+            *value = Complex::new((frame[0] + frame[1]) / 2.0 * weight, 0.0);
+        }
+        self.fft.process(&mut self.buffer);
+
+        // Scaled so a full scale sine comes out at 1
+        let scale = 2.0 / self.window.iter().sum::<f32>();
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f32;
+        let bin_width = sample_rate / FFT_SIZE as f32;
+        (0..BANDS)
+            .map(|band| {
+                let low = (band_edge(band, sample_rate) / bin_width) as usize;
+                // Narrow low bands get at least the bin they start in
+                let high = ((band_edge(band + 1, sample_rate) / bin_width) as usize)
+                    .clamp(low + 1, FFT_SIZE / 2);
+                let magnitude = self.buffer[low..high]
+                    .iter()
+                    .map(|value| value.norm())
+                    .fold(0.0, f32::max);
+                height(magnitude * scale)
+            })
+            .collect()
+    }
+}
+
+// The lowest frequency of a band. The bands split the range from
+// `MIN_FREQUENCY` up to half the sample rate evenly in octaves.
+fn band_edge(band: usize, sample_rate: f32) -> f32 {
+    let top = sample_rate / 2.0;
+    MIN_FREQUENCY * (top / MIN_FREQUENCY).powf(band as f32 / BANDS as f32)
+}
+
+// Where an amplitude sits between the floor and full scale, in dB.
+fn height(amplitude: f32) -> f32 {
+    let db = 20.0 * amplitude.max(1e-9).log10();
+    ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+
+    fn push_sine(tap: &mut SampleTap, frequency: f32, frames: usize) {
+        for i in 0..frames {
+            let sample = (2.0 * PI * frequency * i as f32 / RATE as f32).sin();
+            tap.push_frame(&[sample]);
+        }
+    }
+
+    #[test]
+    fn test_spectrum_finds_tone() {
+        let (mut visualizer, mut tap) = Visualizer::new();
+        tap.set_sample_rate(RATE);
+        push_sine(&mut tap, 1000.0, FFT_SIZE);
+        visualizer.update(0.0);
+
+        let bands = visualizer.bands();
+        let loudest = (0..BANDS)
+            .max_by(|&a, &b| bands[a].total_cmp(&bands[b]))
+            .unwrap();
+        let rate = RATE as f32;
+        assert!(band_edge(loudest, rate) <= 1000.0 && 1000.0 < band_edge(loudest + 1, rate));
+        assert!(bands[loudest] > 0.95, "{}", bands[loudest]);
+
+        // A full scale sine peaks at 0 dB and is 3 dB lower on average
+        let [level, _] = visualizer.levels();
+        let [peak, _] = visualizer.peaks();
+        assert!((peak - 1.0).abs() < 0.01, "{}", peak);
+        assert!((level - (1.0 - 3.0 / -FLOOR_DB)).abs() < 0.01, "{}", level);
+    }
+
+    #[test]
+    fn test_tap_drops_frames_when_full() {
+        let (mut visualizer, mut tap) = Visualizer::new();
+        push_sine(&mut tap, 440.0, TAP_FRAMES * 2);
+        let mut received = 0;
+        while visualizer.consumer.pop().is_some() {
+            received += 1;
+        }
+        assert_eq!(received, TAP_FRAMES);
+    }
+}
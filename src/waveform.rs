@@ -0,0 +1,123 @@
+use crate::decoder::{DecodeError, TrackDecoder};
+use rodio::Source;
+
+/// The number of columns a waveform is reduced to, enough for a seek bar
+/// across a wide window.
+pub const COLUMNS: usize = 800;
+
+// The audio is first split into blocks this long, as the length of a track
+// isn't always known before it has been decoded.
+const BLOCK_SECONDS: f32 = 0.01;
+
+/// An overview of a track's audio: the lowest and highest sample in each of
+/// its columns, from -1 to 1. Tracks shorter than `COLUMNS` blocks have
+/// fewer columns.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Waveform {
+    pub columns: Vec<[f32; 2]>,
+}
+
+impl Waveform {
+    /// Packs the waveform into a byte per value, for storing.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.columns
+            .iter()
+            .flatten()
+            .map(|value| (value.clamp(-1.0, 1.0) * 127.0).round() as i8 as u8)
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Waveform {
+        let columns = bytes
+            .chunks_exact(2)
+            .map(|pair| [pair[0] as i8 as f32 / 127.0, pair[1] as i8 as f32 / 127.0])
+            .collect();
+        Waveform { columns }
+    }
+
+    /// The largest sample anywhere in the track, for scaling quiet tracks up.
+    pub fn loudest(&self) -> f32 {
+        self.columns
+            .iter()
+            .flatten()
+            .fold(0.0, |loudest, value| loudest.max(value.abs()))
+    }
+}
+
+/// Decodes a whole file to draw its waveform. All channels are drawn on top
+/// of each other.
+pub fn analyze(file_path: &str) -> Result<Waveform, DecodeError> {
+    let decoder = TrackDecoder::open(file_path)?;
+    let channels = decoder.channels().max(1) as usize;
+    let block_samples = ((decoder.sample_rate() as f32 * BLOCK_SECONDS) as usize).max(1) * channels;
+
+    let mut blocks = Vec::new();
+    let mut block = [f32::MAX, f32::MIN];
+    let mut done = 0;
+    for sample in decoder {
+        block = [block[0].min(sample), block[1].max(sample)];
+        done += 1;
+        if done == block_samples {
+            blocks.push(block);
+            block = [f32::MAX, f32::MIN];
+            done = 0;
+        }
+    }
+    if done > 0 {
+        blocks.push(block);
+    }
+    Ok(reduce(&blocks, COLUMNS))
+}
+
+// Merges `blocks` into at most `columns` columns.
+fn reduce(blocks: &[[f32; 2]], columns: usize) -> Waveform {
+    if blocks.len() <= columns {
+        return Waveform {
+            columns: blocks.to_vec(),
+        };
+    }
+    let columns = (0..columns)
+        .map(|column| {
+            let start = column * blocks.len() / columns;
+            let end = (column + 1) * blocks.len() / columns;
+            blocks[start..end]
+                .iter()
+                .fold([f32::MAX, f32::MIN], |merged, block| {
+                    [merged[0].min(block[0]), merged[1].max(block[1])]
+                })
+        })
+        .collect();
+    Waveform { columns }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze_fills_columns() {
+        let waveform = analyze("assets/tracks/CantinaBand60.wav").unwrap();
+        assert_eq!(waveform.columns.len(), COLUMNS);
+        assert!(waveform
+            .columns
+            .iter()
+            .all(|&[min, max]| -1.0 <= min && min <= max && max <= 1.0));
+        assert!(waveform.loudest() > 0.1);
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let waveform = Waveform {
+            columns: vec![[-1.0, 1.0], [-0.5, 0.25], [0.0, 0.0]],
+        };
+        let read = Waveform::from_bytes(&waveform.to_bytes());
+        for (read, written) in read
+            .columns
+            .iter()
+            .flatten()
+            .zip(waveform.columns.iter().flatten())
+        {
+            assert!((read - written).abs() < 0.01, "{} {}", read, written);
+        }
+    }
+}